@@ -1,15 +1,421 @@
-// macro_rules! grow_capacity {
-//     ($size:expr) => {
-//         if $size < 8 {
-//             8
-//         } else {
-//             $size * 2
-//         }
-//     };
-// }
-
-// macro_rules! grow_array {
-//     ($arr:expr, $count:expr, $v:expr) => {
-//         $arr.resize($count, $v)
-//     };
-// }
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+// values are reference counted, which frees most garbage as soon as
+// it's dropped. what refcounting can't free is a cycle, and closures
+// make those easy: a closure that captures a variable holding itself
+// keeps itself alive forever. so the heap also keeps a weak handle to
+// everything the VM allocates, and a tracing collector finds the
+// objects that nothing reachable points at. cycles can only be formed
+// through upvalues (everything else is immutable once built), so
+// emptying the unreachable upvalues breaks them, and refcounting does
+// the rest.
+//
+// collections share most of their nodes with the versions they were
+// built from, so each one is only charged for the nodes it added, and
+// a collection sizes up what's really live by counting each node it
+// reaches once, however many versions share it.
+
+// start collecting once this much is live
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+enum Object {
+    String(Weak<str>),
+    Closure(Weak<crate::value::Closure>),
+    Upvalue(Weak<RefCell<crate::value::Upvalue>>),
+    Vector(Weak<crate::collections::Vector>),
+    Map(Weak<crate::collections::Map>),
+    Set(Weak<crate::collections::Set>),
+}
+
+struct Allocation {
+    object: Object,
+    size: usize,
+}
+
+// something marked but whose references haven't been traced yet
+enum Gray {
+    Closure(Rc<crate::value::Closure>),
+    Upvalue(Rc<RefCell<crate::value::Upvalue>>),
+    Vector(Rc<crate::collections::Vector>),
+    Map(Rc<crate::collections::Map>),
+    Set(Rc<crate::collections::Set>),
+}
+
+pub struct Heap {
+    // keyed by address, which is how we recognise an object when we
+    // reach it from a root
+    objects: HashMap<usize, Allocation>,
+    marked: HashSet<usize>,
+    gray: Vec<Gray>,
+
+    // the bytes in the collections traced so far this cycle
+    traced: usize,
+
+    pub bytes_allocated: usize,
+    pub next_gc: usize,
+    pub collections: usize,
+    pub bytes_freed: usize,
+
+    // collect at every opportunity, to shake out missing roots
+    pub stress: bool,
+
+    // anything allocated since the last collection?
+    dirty: bool,
+}
+
+pub fn init_heap() -> Heap {
+    Heap {
+        objects: HashMap::new(),
+        marked: HashSet::new(),
+        gray: Vec::new(),
+        traced: 0,
+        bytes_allocated: 0,
+        next_gc: GC_INITIAL_THRESHOLD,
+        collections: 0,
+        bytes_freed: 0,
+        stress: cfg!(feature = "stress_gc"),
+        dirty: false,
+    }
+}
+
+fn address<T: ?Sized>(ptr: *const T) -> usize {
+    ptr as *const () as usize
+}
+
+impl Heap {
+    fn track(&mut self, addr: usize, object: Object, size: usize) {
+        self.objects.insert(addr, Allocation {
+            object,
+            size,
+        });
+        self.bytes_allocated += size;
+        self.dirty = true;
+    }
+
+    pub fn alloc_string(&mut self, s: String) -> Rc<str> {
+        let size = std::mem::size_of::<Rc<str>>() + s.len();
+        let rc: Rc<str> = s.into();
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::String(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn alloc_closure(&mut self, closure: crate::value::Closure) -> Rc<crate::value::Closure> {
+        let size = std::mem::size_of::<crate::value::Closure>()
+            + closure.upvalues.len() * std::mem::size_of::<Rc<RefCell<crate::value::Upvalue>>>();
+        let rc = Rc::new(closure);
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::Closure(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn alloc_upvalue(&mut self, upvalue: crate::value::Upvalue) -> Rc<RefCell<crate::value::Upvalue>> {
+        let size = std::mem::size_of::<RefCell<crate::value::Upvalue>>();
+        let rc = Rc::new(RefCell::new(upvalue));
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::Upvalue(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn alloc_vector(&mut self, vector: crate::collections::Vector) -> Rc<crate::collections::Vector> {
+        let size = vector.fresh_size();
+        let rc = Rc::new(vector);
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::Vector(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn alloc_map(&mut self, map: crate::collections::Map) -> Rc<crate::collections::Map> {
+        let size = map.fresh_size();
+        let rc = Rc::new(map);
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::Map(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn alloc_set(&mut self, set: crate::collections::Set) -> Rc<crate::collections::Set> {
+        let size = set.fresh_size();
+        let rc = Rc::new(set);
+        self.track(address(Rc::as_ptr(&rc)),
+                   Object::Set(Rc::downgrade(&rc)),
+                   size);
+        rc
+    }
+
+    pub fn should_collect(&self) -> bool {
+        if self.stress {
+            self.dirty
+        } else {
+            self.bytes_allocated > self.next_gc
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    // true if this is the first time we've seen it this cycle
+    fn mark(&mut self, addr: usize) -> bool {
+        self.marked.insert(addr)
+    }
+
+    // the same, for a node inside a collection
+    pub fn mark_node(&mut self, addr: usize) -> bool {
+        self.mark(addr)
+    }
+
+    pub fn mark_value(&mut self, value: &crate::value::ValueType) {
+        match value {
+            crate::value::ValueType::STRING(s) => {
+                self.mark(address(Rc::as_ptr(s)));
+            },
+            crate::value::ValueType::CLOSURE(c) => self.mark_closure(c),
+
+            // collections can hold closures, so they have to be
+            // traced too
+            crate::value::ValueType::VECTOR(v) if self.mark(address(Rc::as_ptr(v))) =>
+                self.gray.push(Gray::Vector(Rc::clone(v))),
+            crate::value::ValueType::MAP(m) if self.mark(address(Rc::as_ptr(m))) =>
+                self.gray.push(Gray::Map(Rc::clone(m))),
+            crate::value::ValueType::SET(s) if self.mark(address(Rc::as_ptr(s))) =>
+                self.gray.push(Gray::Set(Rc::clone(s))),
+            _ => ()
+        }
+    }
+
+    pub fn mark_closure(&mut self, closure: &Rc<crate::value::Closure>) {
+        if self.mark(address(Rc::as_ptr(closure))) {
+            self.gray.push(Gray::Closure(Rc::clone(closure)));
+        }
+    }
+
+    pub fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<crate::value::Upvalue>>) {
+        if self.mark(address(Rc::as_ptr(upvalue))) {
+            self.gray.push(Gray::Upvalue(Rc::clone(upvalue)));
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(gray) = self.gray.pop() {
+            match gray {
+                Gray::Closure(c) => {
+                    for upvalue in c.upvalues.iter() {
+                        self.mark_upvalue(upvalue);
+                    }
+                },
+                Gray::Upvalue(u) => {
+                    if let crate::value::Upvalue::Closed(v) = &*u.borrow() {
+                        self.mark_value(v);
+                    }
+                },
+                Gray::Vector(v) => self.traced += v.trace(self),
+                Gray::Map(m) => self.traced += m.trace(self),
+                Gray::Set(s) => self.traced += s.trace(self),
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let marked = std::mem::take(&mut self.marked);
+        let mut live = std::mem::take(&mut self.traced);
+
+        self.objects.retain(|addr, allocation| {
+            let alive = match &allocation.object {
+                Object::String(w) => w.strong_count() > 0,
+                Object::Closure(w) => w.strong_count() > 0,
+                Object::Upvalue(w) => w.strong_count() > 0,
+                Object::Vector(w) => w.strong_count() > 0,
+                Object::Map(w) => w.strong_count() > 0,
+                Object::Set(w) => w.strong_count() > 0,
+            };
+
+            // already freed by its refcount dropping to zero
+            if !alive {
+                return false;
+            }
+
+            if marked.contains(addr) {
+                // collections were sized up as they were traced
+                if !matches!(allocation.object,
+                             Object::Vector(_) | Object::Map(_) | Object::Set(_)) {
+                    live += allocation.size;
+                }
+                return true;
+            }
+
+            // unreachable but still alive, so it's part of a
+            // cycle. emptying the upvalue lets refcounting take the
+            // cycle apart
+            if let Object::Upvalue(w) = &allocation.object {
+                if let Some(u) = w.upgrade() {
+                    *u.borrow_mut() = crate::value::Upvalue::Closed(
+                        crate::value::ValueType::NIL);
+                }
+            }
+            false
+        });
+
+        self.bytes_freed += self.bytes_allocated.saturating_sub(live);
+        self.bytes_allocated = live;
+    }
+}
+
+// everything the VM can reach directly: the stack, globals, the
+// closures of running functions, and upvalues still pointing into
+// the stack
+fn mark_roots(vm: &mut crate::vm::VM) {
+    for v in vm.stack.iter() {
+        vm.heap.mark_value(v);
+    }
+
+    for v in vm.globals.values.iter().flatten() {
+        vm.heap.mark_value(v);
+    }
+
+    for frame in vm.frames.iter() {
+        vm.heap.mark_closure(&frame.closure);
+    }
+
+    for upvalue in vm.open_upvalues.iter() {
+        vm.heap.mark_upvalue(upvalue);
+    }
+}
+
+pub fn collect_garbage(vm: &mut crate::vm::VM) {
+    mark_roots(vm);
+    vm.heap.trace_references();
+    vm.heap.sweep();
+
+    vm.heap.next_gc = std::cmp::max(vm.heap.bytes_allocated * GC_HEAP_GROW_FACTOR,
+                                    GC_INITIAL_THRESHOLD);
+    vm.heap.collections += 1;
+    vm.heap.dirty = false;
+}
+
+#[cfg(test)]
+mod tests {
+    // about 4MB of strings that nothing holds on to, so the collector
+    // runs at least once whether or not we're stressing it
+    const GARBAGE: &str = "(loop [i 0 s \"x\"] (if (< i 22) (recur (+ i 1) (+ s s)) nil))";
+
+    // runs `source`, which has to evaluate to [result (gc-stats)], and
+    // checks that the collector ran and freed something along the way
+    fn run(source: &str) -> (crate::value::ValueType, String) {
+        let mut vm = crate::vm::init_vm();
+        let value = match vm.interpret(source, "<test>") {
+            Ok(value) => value,
+            Err(err) => panic!("{}", err)
+        };
+
+        let (result, stats) = match &value {
+            crate::value::ValueType::VECTOR(v) if v.len() == 2 =>
+                (v.get(0).unwrap().clone(), v.get(1).unwrap().clone()),
+            _ => panic!("expected [result (gc-stats)]")
+        };
+        let stats = match &stats {
+            crate::value::ValueType::STRING(s) => s.to_string(),
+            _ => panic!("expected (gc-stats) to be a string")
+        };
+
+        assert!(stat(&stats, "collections") > 0, "{}", stats);
+        assert!(stat(&stats, "bytes-freed") > 0, "{}", stats);
+        (result, stats)
+    }
+
+    // "bytes-allocated 1234 next-gc ..." => the number after `name`
+    fn stat(stats: &str, name: &str) -> usize {
+        let words: Vec<&str> = stats.split(' ').collect();
+        let i = words.iter().position(|w| *w == name).unwrap();
+        words[i + 1].parse().unwrap()
+    }
+
+    fn assert_int(value: crate::value::ValueType, expected: i64) {
+        match value {
+            crate::value::ValueType::INT(n) => assert_eq!(n, expected),
+            other => panic!("expected {}, got a {}", expected, crate::value::type_name(&other))
+        }
+    }
+
+    #[test]
+    fn closures_captured_in_a_loop_survive_collection() {
+        let source = format!("
+            (def fs (loop [i 0 acc []]
+                      (if (< i 100) (recur (+ i 1) (conj acc (fn [] i))) acc)))
+            {}
+            [(loop [i 0 sum 0] (if (< i 100) (recur (+ i 1) (+ sum ((nth fs i)))) sum))
+             (gc-stats)]", GARBAGE);
+        assert_int(run(&source).0, 4950);
+    }
+
+    #[test]
+    fn closures_stored_in_collections_survive_collection() {
+        let source = format!("
+            (def ops (let [n 10]
+                       {{:add (fn [x] (+ x n))
+                        :all [(fn [x] (* x n))]
+                        :set #{{(fn [] n)}}}}))
+            {}
+            [(+ ((get ops :add) 1)
+                ((nth (get ops :all) 0) 2)
+                (count (get ops :set)))
+             (gc-stats)]", GARBAGE);
+        assert_int(run(&source).0, 32);
+    }
+
+    #[test]
+    fn upvalues_closed_on_recur_survive_collection() {
+        // each closure holds the one before it, and only through a
+        // closed upvalue
+        let source = format!("
+            (def f (loop [i 1 prev nil]
+                     (if (<= i 100)
+                       (recur (+ i 1) (fn [] (+ i (if prev (prev) 0))))
+                       prev)))
+            {}
+            [(f) (gc-stats)]", GARBAGE);
+        assert_int(run(&source).0, 5050);
+    }
+
+    // every version used to be charged for all of its items, so the
+    // collector ran on almost every step and building a collection one
+    // item at a time went quadratic. stressing the collector runs it
+    // on every step anyway
+    #[cfg(not(feature = "stress_gc"))]
+    #[test]
+    fn building_large_collections_collects_rarely() {
+        let source = "
+            (def m (loop [i 0 m {}] (if (< i 20000) (recur (+ i 1) (assoc m i i)) m)))
+            (def v (loop [i 0 v []] (if (< i 20000) (recur (+ i 1) (conj v i)) v)))
+            [(+ (count m) (get m 19999) (count v) (nth v 19999)) (gc-stats)]";
+        let (result, stats) = run(source);
+        assert_int(result, 79998);
+        assert!(stat(&stats, "collections") < 200, "{}", stats);
+    }
+
+    #[test]
+    fn updates_are_charged_for_the_nodes_they_add() {
+        let mut heap = super::init_heap();
+        let items: Vec<crate::value::ValueType> = (0..10000).map(crate::value::ValueType::INT).collect();
+        let entries = items.iter().map(|i| (i.clone(), i.clone())).collect();
+
+        let m = heap.alloc_map(crate::collections::init_map(entries));
+        let before = heap.bytes_allocated;
+        let _m2 = heap.alloc_map(m.assoc(crate::value::ValueType::INT(-1), crate::value::ValueType::NIL));
+        assert!((heap.bytes_allocated - before) * 100 < before,
+                "{} bytes for one assoc on a {} byte map", heap.bytes_allocated - before, before);
+
+        let v = heap.alloc_vector(crate::collections::init_vector(items));
+        let before = heap.bytes_allocated;
+        let _v2 = heap.alloc_vector(v.conj(crate::value::ValueType::NIL));
+        assert!((heap.bytes_allocated - before) * 100 < before,
+                "{} bytes for one conj", heap.bytes_allocated - before);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+
 #[derive(FromPrimitive, ToPrimitive)]
 #[repr(u8)]
 pub enum Opcode {
     OPCONSTANT,
-    OPNEGATE, // unused...
+    OPNEGATE,
     OPADD,
     OPSUBTRACT,
     OPMULTIPLY,
@@ -12,43 +14,273 @@ pub enum Opcode {
     OPTRUE,
     OPFALSE,
     OPNOT,
-    OPEQUAL,
-    OPLT,
+    OPEQUAL, // compares as many values as the next byte says
+    OPLT,    // and so do the other comparisons
     OPGT,
     OPLTE,
     OPGTE,
     OPLEN,
     OPPRINT,
     OPPOP,
-    OPDEF,
-    OPDEFSYM,  // use sym while defining
-    OPSYM,     // resolve sym
-    OPJMPIFFALSE,
-    OPJMP,
+    OPDEFINEGLOBAL, // pop into the global slot in the next byte
+    OPGETGLOBAL,
+    OPJUMPIFFALSE, // forward by the next two bytes
+    OPJUMP,
+    OPGETLOCAL,
+    OPSETLOCAL,
+    OPCALL,
+    OPCLOSURE,
+    OPGETUPVALUE,
+    OPCLOSEUPVALUE,
+    OPLOOP,        // back by the next two bytes
+    OPJUMPIFTRUE,  // forward by the next two bytes
+    OPDUP,
+    OPTAILCALL,    // OPCALL, reusing the caller's frame
+
+    // the same as the short forms, but with a three byte constant
+    // index for chunks with more than 256 constants, or a three byte
+    // slot for programs with more than 256 globals
+    OPCONSTANTLONG,
+    OPDEFINEGLOBALLONG,
+    OPGETGLOBALLONG,
+    OPCLOSURELONG,
+
+    // build a collection from the top n values on the stack, n being
+    // the next two bytes. for maps, n counts keys and values
+    OPVECTOR,
+    OPMAP,
+    OPSET,
 }
 
+#[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<u16>,
+
+    // the line each byte of `code` came from, run-length encoded:
+    // one entry for each stretch of bytes from the same line
+    lines: Vec<LineRun>,
+
+    // the rest of where each byte came from, which only errors need.
+    // each time the span changes, we append four varints: how many
+    // bytes of code since the last change, how far the span's start
+    // moved (zigzagged, since it can move back), its length, and its
+    // column. that's a few bytes a change rather than a whole Span
+    positions: Vec<u8>,
+    last_span: Option<crate::scanner::Span>,
+    last_change: usize,
+
+    // everything in a chunk comes from the same file
+    file_id: crate::scanner::FileId,
+
     pub constants: crate::value::Values,
+
+    // where each constant already in the table is, so adding the same
+    // one twice reuses the first entry
+    constant_ixs: HashMap<crate::value::ConstantKey, usize>,
+}
+
+// `line` is where the bytes from `start` up to the next run's start
+// came from
+#[derive(Debug)]
+struct LineRun {
+    start: usize,
+    line: u32,
 }
 
 pub fn init_chunk() -> Chunk {
     Chunk {
         code: vec![0; 0],
-        lines: vec![0; 0],
+        lines: Vec::new(),
+        positions: Vec::new(),
+        last_span: None,
+        last_change: 0,
+        file_id: 0,
         constants: crate::value::init_values(),
+        constant_ixs: HashMap::new(),
     }
 }
 
 impl Chunk {
-    pub fn write_chunk(&mut self, byte: u8, line: u16)  {
-        self.code.push(byte as u8);
-        self.lines.push(line);
+    pub fn write_chunk(&mut self, byte: u8, span: &crate::scanner::Span)  {
+        if self.lines.last().is_none_or(|run| run.line != span.line) {
+            self.lines.push(LineRun {
+                start: self.code.len(),
+                line: span.line,
+            });
+        }
+
+        if self.last_span != Some(*span) {
+            let last_start = self.last_span.map_or(0, |last| last.start);
+            let moved = span.start as i64 - last_start as i64;
+            write_varint(&mut self.positions, (self.code.len() - self.last_change) as u64);
+            write_varint(&mut self.positions, ((moved << 1) ^ (moved >> 63)) as u64);
+            write_varint(&mut self.positions, (span.end - span.start) as u64);
+            write_varint(&mut self.positions, span.col as u64);
+
+            self.last_span = Some(*span);
+            self.last_change = self.code.len();
+            self.file_id = span.file_id;
+        }
+
+        self.code.push(byte);
+    }
+
+    // the line the byte at `offset` came from
+    pub fn line_for(&self, offset: usize) -> u32 {
+        // the last run starting at or before `offset`
+        let ix = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[ix - 1].line
+    }
+
+    // the source the byte at `offset` came from. this decodes
+    // `positions` from the top, which is fine for reporting an error
+    pub fn span_for(&self, offset: usize) -> crate::scanner::Span {
+        let mut cursor = 0;
+        let (mut at, mut start, mut length, mut col) = (0, 0, 0, 0);
+        while cursor < self.positions.len() {
+            at += read_varint(&self.positions, &mut cursor) as usize;
+            if at > offset {
+                break;
+            }
+            let moved = read_varint(&self.positions, &mut cursor);
+            start += ((moved >> 1) as i64) ^ -((moved & 1) as i64);
+            length = read_varint(&self.positions, &mut cursor) as usize;
+            col = read_varint(&self.positions, &mut cursor) as u32;
+        }
+
+        crate::scanner::Span {
+            file_id: self.file_id,
+            start: start as usize,
+            end: start as usize + length,
+            line: self.line_for(offset),
+            col,
+        }
     }
 
     pub fn add_constant(&mut self,
                         value: crate::value::ConstantType) -> usize {
-        self.constants.write_values(value)
+        let key = match value.key() {
+            Some(key) => key,
+            None => return self.constants.write_values(value)
+        };
+
+        if let Some(ix) = self.constant_ixs.get(&key) {
+            return *ix;
+        }
+        let ix = self.constants.write_values(value);
+        self.constant_ixs.insert(key, ix);
+        ix
+    }
+}
+
+// seven bits at a time, low first, with the top bit set on all but
+// the last byte
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_varint(bytes: &[u8], cursor: &mut usize) -> u64 {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*cursor];
+        *cursor += 1;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // a span on `line`, as the compiler would pass it
+    fn span_on(line: u32) -> crate::scanner::Span {
+        crate::scanner::Span {
+            file_id: 0,
+            start: 0,
+            end: 0,
+            line,
+            col: 1,
+        }
+    }
+
+    // writes one byte per entry of `lines`, the way chunks used to
+    // store them, and checks every offset reads back the same line
+    fn round_trip(lines: &[u32]) -> super::Chunk {
+        let mut chunk = super::init_chunk();
+        for (i, line) in lines.iter().enumerate() {
+            chunk.write_chunk(i as u8, &span_on(*line));
+        }
+        for (offset, line) in lines.iter().enumerate() {
+            assert_eq!(chunk.line_for(offset), *line, "at offset {}", offset);
+        }
+        chunk
+    }
+
+    #[test]
+    fn one_byte_chunk() {
+        round_trip(&[7]);
+    }
+
+    #[test]
+    fn one_run() {
+        let chunk = round_trip(&[3, 3, 3, 3, 3]);
+        assert_eq!(chunk.lines.len(), 1);
+    }
+
+    #[test]
+    fn run_boundaries() {
+        // runs of one byte at the start, middle and end, and lines
+        // that come back after a different one
+        let chunk = round_trip(&[1, 2, 2, 2, 3, 2, 2, 5, 5, 1]);
+        assert_eq!(chunk.lines.len(), 6);
+    }
+
+    #[test]
+    fn lines_past_u16() {
+        round_trip(&[65535, 65536, 65536, u32::MAX]);
+    }
+
+    #[test]
+    fn many_runs() {
+        let lines: Vec<u32> = (0..1000).map(|i| i / 7 + i % 3).collect();
+        round_trip(&lines);
+    }
+
+    #[test]
+    fn spans_round_trip() {
+        // (bytes, start, end, line, col): starts that move back, spans
+        // of one byte and of several, and offsets past a varint byte
+        let spans = [(3, 0, 4, 1, 1), (1, 10, 11, 1, 11), (2, 5, 9, 1, 6),
+                     (1, 200, 230, 4, 1), (4, 100000, 100001, 900, 70),
+                     (1, 12, 13, 2, 3)];
+        let mut written = Vec::new();
+        let mut chunk = super::init_chunk();
+        for (bytes, start, end, line, col) in spans.iter() {
+            let span = crate::scanner::Span {
+                file_id: 3,
+                start: *start,
+                end: *end,
+                line: *line,
+                col: *col,
+            };
+            for _ in 0..*bytes {
+                chunk.write_chunk(0, &span);
+                written.push(span);
+            }
+        }
+
+        for (offset, span) in written.iter().enumerate() {
+            assert_eq!(chunk.span_for(offset), *span, "at offset {}", offset);
+        }
+
+        // one change per span, none more than a handful of bytes
+        assert!(chunk.positions.len() <= spans.len() * 6);
     }
 }
@@ -0,0 +1,874 @@
+use std::rc::Rc;
+
+// the runtime collections. they're persistent: every "change" builds
+// a new collection and leaves the old one alone. the new one shares
+// everything it didn't change with the old one, so a change costs
+// O(log32 n) rather than a copy.
+//
+// all the changes are written as in-place updates through
+// `Rc::make_mut`, which copies a node only if something else can see
+// it. so a persistent update is a transient update on a fresh handle
+// (copying just the path to the change), and a transient, which
+// nothing else can see, gets to update in place after its first touch
+// of each node
+
+const BITS: u32 = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+// -- vectors: a 32-way trie of the items, with the last (up to) 32
+// kept out of the trie in `tail`, so most appends don't touch the trie
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum VectorNode {
+    Branch(Vec<Rc<VectorNode>>),
+    Leaf(Vec<crate::value::ValueType>),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Vector {
+    count: usize,
+
+    // how far to shift an index to find its slot in the root
+    shift: u32,
+    root: Rc<VectorNode>,
+    tail: Vec<crate::value::ValueType>,
+}
+
+// a vector being built in place. nothing else can see it until
+// `persistent` hands it over
+pub struct TransientVector {
+    vector: Vector,
+}
+
+fn empty_vector() -> Vector {
+    Vector {
+        count: 0,
+        shift: BITS,
+        root: Rc::new(VectorNode::Branch(Vec::new())),
+        tail: Vec::new(),
+    }
+}
+
+pub fn init_vector(items: Vec<crate::value::ValueType>) -> Vector {
+    let mut t = empty_vector().transient();
+    for item in items {
+        t.conj(item);
+    }
+    t.persistent()
+}
+
+impl Vector {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // the index of the first item in the tail
+    fn tail_offset(&self) -> usize {
+        if self.count < WIDTH {
+            0
+        } else {
+            ((self.count - 1) >> BITS) << BITS
+        }
+    }
+
+    // the 32 (or fewer) items sharing a leaf with `index`
+    fn leaf_for(&self, index: usize) -> &[crate::value::ValueType] {
+        if index >= self.tail_offset() {
+            return &self.tail;
+        }
+
+        let mut node = &self.root;
+        let mut level = self.shift;
+        loop {
+            match &**node {
+                VectorNode::Branch(children) => {
+                    node = &children[(index >> level) & MASK];
+                    level -= BITS;
+                },
+                VectorNode::Leaf(items) => return items
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&crate::value::ValueType> {
+        if index >= self.count {
+            return None;
+        }
+        self.leaf_for(index).get(index & MASK)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &crate::value::ValueType> {
+        (0..self.count)
+            .step_by(WIDTH)
+            .flat_map(move |i| self.leaf_for(i).iter())
+    }
+
+    // the bytes this version doesn't share with any other. for one
+    // made by a persistent update, that's the path the update copied
+    // (and the tail); for one built from scratch, it's everything
+    pub fn fresh_size(&self) -> usize {
+        std::mem::size_of::<Vector>()
+            + self.tail.len() * std::mem::size_of::<crate::value::ValueType>()
+            + fresh_vector_node_size(&self.root)
+    }
+
+    // marks the items, skipping nodes the heap has already traced
+    // through another version. returns the bytes it hadn't seen yet
+    pub fn trace(&self, heap: &mut crate::alloc::Heap) -> usize {
+        for item in self.tail.iter() {
+            heap.mark_value(item);
+        }
+        std::mem::size_of::<Vector>()
+            + self.tail.len() * std::mem::size_of::<crate::value::ValueType>()
+            + trace_vector_node(&self.root, heap)
+    }
+
+    pub fn transient(&self) -> TransientVector {
+        TransientVector {
+            vector: self.clone(),
+        }
+    }
+
+    // a new vector with `value` on the end
+    pub fn conj(&self, value: crate::value::ValueType) -> Vector {
+        let mut t = self.transient();
+        t.conj(value);
+        t.persistent()
+    }
+
+    // a new vector with `value` at `index`. `index` may be one past
+    // the end, which appends
+    pub fn assoc(&self, index: usize, value: crate::value::ValueType) -> Option<Vector> {
+        let mut t = self.transient();
+        if t.assoc(index, value) {
+            Some(t.persistent())
+        } else {
+            None
+        }
+    }
+}
+
+fn vector_node_size(node: &VectorNode) -> usize {
+    std::mem::size_of::<VectorNode>() + match node {
+        VectorNode::Branch(children) => children.len() * std::mem::size_of::<Rc<VectorNode>>(),
+        VectorNode::Leaf(items) => items.len() * std::mem::size_of::<crate::value::ValueType>(),
+    }
+}
+
+// anything with another owner is shared, along with everything under it
+fn fresh_vector_node_size(node: &Rc<VectorNode>) -> usize {
+    if Rc::strong_count(node) > 1 {
+        return 0;
+    }
+    vector_node_size(node) + match &**node {
+        VectorNode::Branch(children) => children.iter().map(fresh_vector_node_size).sum(),
+        VectorNode::Leaf(_) => 0,
+    }
+}
+
+fn trace_vector_node(node: &Rc<VectorNode>, heap: &mut crate::alloc::Heap) -> usize {
+    if !heap.mark_node(Rc::as_ptr(node) as *const () as usize) {
+        return 0;
+    }
+    match &**node {
+        VectorNode::Branch(children) => vector_node_size(node)
+            + children.iter().map(|child| trace_vector_node(child, heap)).sum::<usize>(),
+        VectorNode::Leaf(items) => {
+            for item in items.iter() {
+                heap.mark_value(item);
+            }
+            vector_node_size(node)
+        }
+    }
+}
+
+// a chain of branches `level` deep, ending in `node`
+fn new_path(level: u32, node: Rc<VectorNode>) -> Rc<VectorNode> {
+    if level == 0 {
+        node
+    } else {
+        Rc::new(VectorNode::Branch(vec![new_path(level - BITS, node)]))
+    }
+}
+
+// put the full `leaf` in as item `index` onwards
+fn push_leaf(node: &mut Rc<VectorNode>, level: u32, index: usize, leaf: Rc<VectorNode>) {
+    if let VectorNode::Branch(children) = Rc::make_mut(node) {
+        let i = (index >> level) & MASK;
+        if level == BITS {
+            children.push(leaf);
+        } else if i < children.len() {
+            push_leaf(&mut children[i], level - BITS, index, leaf);
+        } else {
+            children.push(new_path(level - BITS, leaf));
+        }
+    }
+}
+
+fn set_in(node: &mut Rc<VectorNode>, level: u32, index: usize, value: crate::value::ValueType) {
+    match Rc::make_mut(node) {
+        VectorNode::Branch(children) =>
+            set_in(&mut children[(index >> level) & MASK], level - BITS, index, value),
+        VectorNode::Leaf(items) => items[index & MASK] = value,
+    }
+}
+
+impl TransientVector {
+    pub fn conj(&mut self, value: crate::value::ValueType) {
+        let v = &mut self.vector;
+
+        // room in the tail
+        if v.count - v.tail_offset() < WIDTH {
+            v.tail.push(value);
+            v.count += 1;
+            return;
+        }
+
+        // otherwise the full tail goes into the trie, growing a new
+        // root if the old one is full
+        let leaf = Rc::new(VectorNode::Leaf(std::mem::take(&mut v.tail)));
+        let tail_offset = v.tail_offset();
+        if (v.count >> BITS) > (1 << v.shift) {
+            let root = std::mem::replace(&mut v.root, Rc::new(VectorNode::Branch(Vec::new())));
+            v.root = Rc::new(VectorNode::Branch(vec![root, new_path(v.shift, leaf)]));
+            v.shift += BITS;
+        } else {
+            push_leaf(&mut v.root, v.shift, tail_offset, leaf);
+        }
+
+        v.tail.push(value);
+        v.count += 1;
+    }
+
+    // false if `index` is out of range
+    pub fn assoc(&mut self, index: usize, value: crate::value::ValueType) -> bool {
+        let v = &mut self.vector;
+        if index == v.count {
+            self.conj(value);
+            return true;
+        }
+        if index > v.count {
+            return false;
+        }
+
+        let tail_offset = v.tail_offset();
+        if index >= tail_offset {
+            v.tail[index - tail_offset] = value;
+        } else {
+            set_in(&mut v.root, v.shift, index, value);
+        }
+        true
+    }
+
+    pub fn persistent(self) -> Vector {
+        self.vector
+    }
+}
+
+// -- maps: a hash array mapped trie. each level takes 5 bits of the
+// key's hash; a bitmap says which of the 32 possible slots are in
+// use, so unused ones don't take any space
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum MapNode {
+    Bitmap(u32, Vec<MapEntry>),
+
+    // keys whose whole hashes are the same
+    Collision(u64, Vec<(crate::value::ValueType, crate::value::ValueType)>),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+enum MapEntry {
+    Pair(u64, crate::value::ValueType, crate::value::ValueType),
+    Node(Rc<MapNode>),
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Map {
+    count: usize,
+    root: Rc<MapNode>,
+}
+
+pub struct TransientMap {
+    map: Map,
+}
+
+fn empty_map() -> Map {
+    Map {
+        count: 0,
+        root: Rc::new(MapNode::Bitmap(0, Vec::new())),
+    }
+}
+
+// later entries win over earlier ones with the same key
+pub fn init_map(entries: Vec<(crate::value::ValueType, crate::value::ValueType)>) -> Map {
+    let mut t = empty_map().transient();
+    for (k, v) in entries {
+        t.assoc(k, v);
+    }
+    t.persistent()
+}
+
+fn fragment(hash: u64, shift: u32) -> u32 {
+    ((hash >> shift) as usize & MASK) as u32
+}
+
+// where `bit` goes in the bitmap's entries
+fn bitmap_index(bitmap: u32, bit: u32) -> usize {
+    (bitmap & (bit - 1)).count_ones() as usize
+}
+
+fn node_get<'a>(node: &'a MapNode,
+                shift: u32,
+                hash: u64,
+                key: &crate::value::ValueType) -> Option<&'a crate::value::ValueType> {
+    match node {
+        MapNode::Bitmap(bitmap, entries) => {
+            let bit = 1 << fragment(hash, shift);
+            if bitmap & bit == 0 {
+                return None;
+            }
+            match &entries[bitmap_index(*bitmap, bit)] {
+                MapEntry::Pair(h, k, v) if *h == hash && crate::value::values_equal(k, key) =>
+                    Some(v),
+                MapEntry::Pair(..) => None,
+                MapEntry::Node(child) => node_get(child, shift + BITS, hash, key),
+            }
+        },
+        MapNode::Collision(_, pairs) => pairs.iter()
+            .find(|(k, _)| crate::value::values_equal(k, key))
+            .map(|(_, v)| v)
+    }
+}
+
+// a node holding two entries with different keys
+fn merge_pairs(shift: u32,
+               a: (u64, crate::value::ValueType, crate::value::ValueType),
+               b: (u64, crate::value::ValueType, crate::value::ValueType)) -> MapNode {
+    if a.0 == b.0 {
+        return MapNode::Collision(a.0, vec![(a.1, a.2), (b.1, b.2)]);
+    }
+
+    let (fa, fb) = (fragment(a.0, shift), fragment(b.0, shift));
+    if fa == fb {
+        let child = merge_pairs(shift + BITS, a, b);
+        return MapNode::Bitmap(1 << fa, vec![MapEntry::Node(Rc::new(child))]);
+    }
+
+    let bitmap = (1 << fa) | (1 << fb);
+    let a = MapEntry::Pair(a.0, a.1, a.2);
+    let b = MapEntry::Pair(b.0, b.1, b.2);
+    if fa < fb {
+        MapNode::Bitmap(bitmap, vec![a, b])
+    } else {
+        MapNode::Bitmap(bitmap, vec![b, a])
+    }
+}
+
+// true if `key` is new
+fn node_assoc(node: &mut Rc<MapNode>,
+              shift: u32,
+              hash: u64,
+              key: crate::value::ValueType,
+              value: crate::value::ValueType) -> bool {
+    // a key with a different hash has ended up at a collision node,
+    // so push the collision down a level
+    if let MapNode::Collision(h, _) = &**node {
+        if *h != hash {
+            let bit = 1 << fragment(*h, shift);
+            let collision = MapEntry::Node(Rc::clone(node));
+            *node = Rc::new(MapNode::Bitmap(bit, vec![collision]));
+        }
+    }
+
+    match Rc::make_mut(node) {
+        MapNode::Bitmap(bitmap, entries) => {
+            let bit = 1 << fragment(hash, shift);
+            let i = bitmap_index(*bitmap, bit);
+
+            if *bitmap & bit == 0 {
+                entries.insert(i, MapEntry::Pair(hash, key, value));
+                *bitmap |= bit;
+                return true;
+            }
+
+            match &mut entries[i] {
+                MapEntry::Pair(h, k, v) if *h == hash && crate::value::values_equal(k, &key) => {
+                    *v = value;
+                    false
+                },
+                MapEntry::Pair(h, k, v) => {
+                    let existing = (*h, k.clone(), v.clone());
+                    let child = merge_pairs(shift + BITS, existing, (hash, key, value));
+                    entries[i] = MapEntry::Node(Rc::new(child));
+                    true
+                },
+                MapEntry::Node(child) => node_assoc(child, shift + BITS, hash, key, value),
+            }
+        },
+        MapNode::Collision(_, pairs) => {
+            match pairs.iter().position(|(k, _)| crate::value::values_equal(k, &key)) {
+                Some(i) => {
+                    pairs[i].1 = value;
+                    false
+                },
+                None => {
+                    pairs.push((key, value));
+                    true
+                }
+            }
+        }
+    }
+}
+
+fn map_node_size(node: &MapNode) -> usize {
+    std::mem::size_of::<MapNode>() + match node {
+        MapNode::Bitmap(_, entries) => entries.len() * std::mem::size_of::<MapEntry>(),
+        MapNode::Collision(_, pairs) =>
+            pairs.len() * 2 * std::mem::size_of::<crate::value::ValueType>(),
+    }
+}
+
+fn fresh_map_node_size(node: &Rc<MapNode>) -> usize {
+    if Rc::strong_count(node) > 1 {
+        return 0;
+    }
+    map_node_size(node) + match &**node {
+        MapNode::Bitmap(_, entries) => entries.iter()
+            .map(|entry| match entry {
+                MapEntry::Node(child) => fresh_map_node_size(child),
+                MapEntry::Pair(..) => 0,
+            })
+            .sum(),
+        MapNode::Collision(..) => 0,
+    }
+}
+
+fn trace_map_node(node: &Rc<MapNode>, heap: &mut crate::alloc::Heap) -> usize {
+    if !heap.mark_node(Rc::as_ptr(node) as *const () as usize) {
+        return 0;
+    }
+    let mut size = map_node_size(node);
+    match &**node {
+        MapNode::Bitmap(_, entries) => {
+            for entry in entries.iter() {
+                match entry {
+                    MapEntry::Pair(_, k, v) => {
+                        heap.mark_value(k);
+                        heap.mark_value(v);
+                    },
+                    MapEntry::Node(child) => size += trace_map_node(child, heap),
+                }
+            }
+        },
+        MapNode::Collision(_, pairs) => {
+            for (k, v) in pairs.iter() {
+                heap.mark_value(k);
+                heap.mark_value(v);
+            }
+        }
+    }
+    size
+}
+
+// walks the trie depth first
+pub struct MapIter<'a> {
+    stack: Vec<MapNodeIter<'a>>,
+}
+
+enum MapNodeIter<'a> {
+    Bitmap(std::slice::Iter<'a, MapEntry>),
+    Collision(std::slice::Iter<'a, (crate::value::ValueType, crate::value::ValueType)>),
+}
+
+fn map_node_iter(node: &MapNode) -> MapNodeIter<'_> {
+    match node {
+        MapNode::Bitmap(_, entries) => MapNodeIter::Bitmap(entries.iter()),
+        MapNode::Collision(_, pairs) => MapNodeIter::Collision(pairs.iter()),
+    }
+}
+
+impl<'a> Iterator for MapIter<'a> {
+    type Item = (&'a crate::value::ValueType, &'a crate::value::ValueType);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match self.stack.last_mut()? {
+                MapNodeIter::Bitmap(entries) => match entries.next() {
+                    Some(MapEntry::Pair(_, k, v)) => return Some((k, v)),
+                    Some(MapEntry::Node(child)) => Some(map_node_iter(child)),
+                    None => None
+                },
+                MapNodeIter::Collision(pairs) => match pairs.next() {
+                    Some((k, v)) => return Some((k, v)),
+                    None => None
+                }
+            };
+
+            match next {
+                Some(child) => self.stack.push(child),
+                None => { self.stack.pop(); }
+            }
+        }
+    }
+}
+
+impl Map {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, key: &crate::value::ValueType) -> Option<&crate::value::ValueType> {
+        node_get(&self.root, 0, crate::value::hash_value(key), key)
+    }
+
+    pub fn contains_key(&self, key: &crate::value::ValueType) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> MapIter<'_> {
+        MapIter {
+            stack: vec![map_node_iter(&self.root)],
+        }
+    }
+
+    // see `Vector::fresh_size`
+    pub fn fresh_size(&self) -> usize {
+        std::mem::size_of::<Map>() + fresh_map_node_size(&self.root)
+    }
+
+    // see `Vector::trace`
+    pub fn trace(&self, heap: &mut crate::alloc::Heap) -> usize {
+        std::mem::size_of::<Map>() + trace_map_node(&self.root, heap)
+    }
+
+    pub fn transient(&self) -> TransientMap {
+        TransientMap {
+            map: self.clone(),
+        }
+    }
+
+    // a new map with `key` mapped to `value`
+    pub fn assoc(&self, key: crate::value::ValueType, value: crate::value::ValueType) -> Map {
+        let mut t = self.transient();
+        t.assoc(key, value);
+        t.persistent()
+    }
+}
+
+impl TransientMap {
+    pub fn assoc(&mut self, key: crate::value::ValueType, value: crate::value::ValueType) {
+        let hash = crate::value::hash_value(&key);
+        self.assoc_hashed(hash, key, value);
+    }
+
+    // `hash` has to be `key`'s. tests pick their own to force collisions
+    fn assoc_hashed(&mut self,
+                    hash: u64,
+                    key: crate::value::ValueType,
+                    value: crate::value::ValueType) {
+        if node_assoc(&mut self.map.root, 0, hash, key, value) {
+            self.map.count += 1;
+        }
+    }
+
+    pub fn persistent(self) -> Map {
+        self.map
+    }
+}
+
+// -- sets: maps from each item to nil
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Set {
+    map: Map,
+}
+
+pub struct TransientSet {
+    map: TransientMap,
+}
+
+// duplicates are dropped
+pub fn init_set(items: Vec<crate::value::ValueType>) -> Set {
+    let mut t = Set { map: empty_map() }.transient();
+    for item in items {
+        t.conj(item);
+    }
+    t.persistent()
+}
+
+impl Set {
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains(&self, value: &crate::value::ValueType) -> bool {
+        self.map.contains_key(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &crate::value::ValueType> {
+        self.map.iter().map(|(k, _)| k)
+    }
+
+    pub fn fresh_size(&self) -> usize {
+        self.map.fresh_size()
+    }
+
+    pub fn trace(&self, heap: &mut crate::alloc::Heap) -> usize {
+        self.map.trace(heap)
+    }
+
+    pub fn transient(&self) -> TransientSet {
+        TransientSet {
+            map: self.map.transient(),
+        }
+    }
+
+    // a new set with `value` in it
+    pub fn conj(&self, value: crate::value::ValueType) -> Set {
+        let mut t = self.transient();
+        t.conj(value);
+        t.persistent()
+    }
+}
+
+impl TransientSet {
+    pub fn conj(&mut self, value: crate::value::ValueType) {
+        self.map.assoc(value, crate::value::ValueType::NIL);
+    }
+
+    pub fn persistent(self) -> Set {
+        Set {
+            map: self.map.persistent(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    fn int(n: i64) -> crate::value::ValueType {
+        crate::value::ValueType::INT(n)
+    }
+
+    fn as_int(value: Option<&crate::value::ValueType>) -> Option<i64> {
+        match value {
+            Some(crate::value::ValueType::INT(n)) => Some(*n),
+            Some(other) => panic!("expected an int, got a {}", crate::value::type_name(other)),
+            None => None
+        }
+    }
+
+    // 0, 1, ... n-1, every item where it should be and nothing after
+    fn check_vector(v: &super::Vector, n: usize) {
+        assert_eq!(v.len(), n);
+        for i in 0..n {
+            assert_eq!(as_int(v.get(i)), Some(i as i64), "at {} of {}", i, n);
+        }
+        assert!(v.get(n).is_none());
+        assert!(v.iter().map(|item| as_int(Some(item)).unwrap()).eq(0..n as i64));
+    }
+
+    #[test]
+    fn vector_sizes_around_trie_boundaries() {
+        // the tail fills at 32, the root first grows at 1056 (a full
+        // 32-leaf root plus a tail), and again at 32800
+        let sizes = [0, 1, 31, 32, 33, 64, 65, 1055, 1056, 1057, 1088, 1089,
+                     32799, 32800, 32801, 32833, 33000];
+        for n in sizes.iter() {
+            check_vector(&super::init_vector((0..*n as i64).map(int).collect()), *n);
+        }
+
+        // and one item at a time, persistently, through all of them
+        let mut v = super::init_vector(Vec::new());
+        for i in 0..33000 {
+            v = v.conj(int(i as i64));
+            if sizes.contains(&(i + 1)) {
+                check_vector(&v, i + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn vector_updates_leave_the_old_version_alone() {
+        let old = super::init_vector((0..1100).map(int).collect());
+
+        let conjed = old.conj(int(1100));
+        check_vector(&conjed, 1101);
+
+        // in the tail, in the trie, and one past the end
+        for i in [1090, 500, 1100].iter() {
+            let new = old.assoc(*i, int(-1)).unwrap();
+            assert_eq!(as_int(new.get(*i)), Some(-1));
+        }
+        assert!(old.assoc(1101, int(-1)).is_none());
+
+        check_vector(&old, 1100);
+    }
+
+    #[test]
+    fn map_updates_leave_the_old_version_alone() {
+        let old = super::init_map((0..1000).map(|i| (int(i), int(i))).collect());
+
+        let new = old.assoc(int(5), int(-5)).assoc(int(1000), int(1000));
+        assert_eq!(new.len(), 1001);
+        assert_eq!(as_int(new.get(&int(5))), Some(-5));
+        assert_eq!(as_int(new.get(&int(1000))), Some(1000));
+
+        assert_eq!(old.len(), 1000);
+        assert_eq!(as_int(old.get(&int(5))), Some(5));
+        assert!(old.get(&int(1000)).is_none());
+
+        let set = super::init_set((0..100).map(int).collect());
+        let bigger = set.conj(int(100));
+        assert_eq!((set.len(), bigger.len()), (100, 101));
+        assert!(!set.contains(&int(100)) && bigger.contains(&int(100)));
+    }
+
+    // a map with each key given the hash next to it
+    fn map_with_hashes(entries: &[(i64, u64)]) -> super::Map {
+        let mut t = super::empty_map().transient();
+        for (key, hash) in entries.iter() {
+            t.assoc_hashed(*hash, int(*key), int(*key * 10));
+        }
+        t.persistent()
+    }
+
+    fn get_hashed(m: &super::Map, key: i64, hash: u64) -> Option<i64> {
+        as_int(super::node_get(&m.root, 0, hash, &int(key)))
+    }
+
+    // the node the first root entry leads to
+    fn first_child(m: &super::Map) -> Rc<super::MapNode> {
+        match &*m.root {
+            super::MapNode::Bitmap(_, entries) => match &entries[0] {
+                super::MapEntry::Node(child) => Rc::clone(child),
+                super::MapEntry::Pair(..) => panic!("expected a node"),
+            },
+            super::MapNode::Collision(..) => panic!("expected a bitmap root"),
+        }
+    }
+
+    #[test]
+    fn keys_with_the_same_hash_share_a_collision_node() {
+        let h = 0b00011_00001;
+        let m = map_with_hashes(&[(1, h), (2, h), (3, h)]);
+
+        assert_eq!(m.len(), 3);
+        match &*first_child(&m) {
+            super::MapNode::Collision(hash, pairs) => assert_eq!((*hash, pairs.len()), (h, 3)),
+            other => panic!("expected a collision node, got {:?}", other),
+        }
+        for key in 1..=3 {
+            assert_eq!(get_hashed(&m, key, h), Some(key * 10));
+        }
+        assert_eq!(get_hashed(&m, 4, h), None);
+
+        // replacing a colliding key doesn't add one
+        let mut t = m.transient();
+        t.assoc_hashed(h, int(2), int(-2));
+        let m = t.persistent();
+        assert_eq!(m.len(), 3);
+        assert_eq!(get_hashed(&m, 2, h), Some(-2));
+    }
+
+    #[test]
+    fn a_new_hash_pushes_a_collision_node_down() {
+        // the same first fragment as `h`, so it ends up at the
+        // collision node, and then a different second one
+        let h = 0b00011_00001;
+        let near = 0b00111_00001;
+        let m = map_with_hashes(&[(1, h), (2, h), (3, near)]);
+
+        assert_eq!(m.len(), 3);
+        match &*first_child(&m) {
+            super::MapNode::Bitmap(bitmap, entries) => {
+                assert_eq!(*bitmap, (1 << 3) | (1 << 7));
+                assert!(matches!(&entries[0],
+                                 super::MapEntry::Node(n) if matches!(**n, super::MapNode::Collision(..))));
+                assert!(matches!(&entries[1], super::MapEntry::Pair(..)));
+            },
+            other => panic!("expected a bitmap node, got {:?}", other),
+        }
+        assert_eq!(get_hashed(&m, 1, h), Some(10));
+        assert_eq!(get_hashed(&m, 2, h), Some(20));
+        assert_eq!(get_hashed(&m, 3, near), Some(30));
+
+        // differing only further down pushes it down more than once
+        let far = h | (1 << 20);
+        let m = map_with_hashes(&[(1, h), (2, h), (3, far)]);
+        assert_eq!(m.len(), 3);
+        assert_eq!(get_hashed(&m, 2, h), Some(20));
+        assert_eq!(get_hashed(&m, 3, far), Some(30));
+        assert_eq!(m.iter().count(), 3);
+    }
+
+    #[test]
+    fn equal_maps_and_sets_hash_the_same_whatever_the_order() {
+        let entries: Vec<(crate::value::ValueType, crate::value::ValueType)> =
+            (0..500).map(|i| (int(i), int(i * i))).collect();
+        let forwards = super::init_map(entries.clone());
+        let backwards = super::init_map(entries.into_iter().rev().collect());
+        let a = crate::value::ValueType::MAP(Rc::new(forwards));
+        let b = crate::value::ValueType::MAP(Rc::new(backwards));
+        assert!(crate::value::values_equal(&a, &b));
+        assert_eq!(crate::value::hash_value(&a), crate::value::hash_value(&b));
+
+        let a = crate::value::ValueType::SET(Rc::new(super::init_set((0..500).map(int).collect())));
+        let b = crate::value::ValueType::SET(Rc::new(super::init_set((0..500).rev().map(int).collect())));
+        assert!(crate::value::values_equal(&a, &b));
+        assert_eq!(crate::value::hash_value(&a), crate::value::hash_value(&b));
+    }
+
+    #[test]
+    fn transients_build_what_persistent_updates_do() {
+        let mut t = super::init_vector(Vec::new()).transient();
+        let mut v = super::init_vector(Vec::new());
+        for i in 0..2000 {
+            t.conj(int(i));
+            v = v.conj(int(i));
+        }
+        let t = t.persistent();
+        check_vector(&t, 2000);
+        check_vector(&v, 2000);
+
+        let mut t = super::init_map(Vec::new()).transient();
+        let mut m = super::init_map(Vec::new());
+        for i in 0..2000 {
+            // every key twice, so some updates replace
+            t.assoc(int(i % 1000), int(i));
+            m = m.assoc(int(i % 1000), int(i));
+        }
+        let t = t.persistent();
+        assert_eq!((t.len(), m.len()), (1000, 1000));
+        for i in 0..1000 {
+            assert_eq!(as_int(t.get(&int(i))), Some(i + 1000));
+            assert_eq!(as_int(m.get(&int(i))), Some(i + 1000));
+        }
+        assert!(crate::value::values_equal(&crate::value::ValueType::MAP(Rc::new(t)),
+                                           &crate::value::ValueType::MAP(Rc::new(m))));
+    }
+}
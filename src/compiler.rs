@@ -1,12 +1,13 @@
 extern crate num_derive;
 use num::{ToPrimitive};
 
-use std::str::FromStr;
 use std::convert::TryFrom;
 use std::rc::Rc;
-use indextree::Arena;
-use indextree::Node;
-use indextree::NodeId;
+
+// a chunk's constant indices have to fit in the three bytes that
+// follow a _LONG instruction
+const MAX_CONSTANTS: usize = 1 << 24;
+const MAX_GLOBALS: usize = 1 << 24;
 
 macro_rules! opcode {
     ($op:tt) => {
@@ -15,721 +16,1191 @@ macro_rules! opcode {
 }
 
 #[derive(Debug)]
-pub struct Generator {
-    pub current: Rc<Option<crate::scanner::Token>>,
+pub struct Generator<'a> {
     pub had_error: bool,
     pub panic_mode: bool,
+    pub error: Option<Box<crate::error::SophieError>>,
+    pub compiler: Compiler,
+
+    // where the source came from, for error messages and for the
+    // functions we build
+    pub file: Rc<str>,
+    pub source: Rc<str>,
+
+    // set by `def` when its value is a `fn` form, so the function
+    // knows its own name
+    pub fn_name: Option<String>,
+
+    // the VM's, so keywords are the same pointer everywhere
+    pub interner: &'a mut crate::interner::Interner,
+
+    // also the VM's, so every chunk puts a global in the same slot
+    pub globals: &'a mut crate::globals::Globals,
 }
 
-fn init_generator() -> Generator {
+fn init_generator<'a>(file: Rc<str>,
+                  source: Rc<str>,
+                  interner: &'a mut crate::interner::Interner,
+                  globals: &'a mut crate::globals::Globals) -> Generator<'a> {
     Generator {
-        current: Rc::new(None),
         had_error: false,
         panic_mode: false,
+        error: None,
+        compiler: init_compiler(),
+        file,
+        source,
+        fn_name: None,
+        interner,
+        globals,
     }
 }
 
+// one of these per function being compiled. the top level of a file
+// gets one too
 #[derive(Debug)]
-pub struct Compiler<'a> {
-    pub local_count: u8,
-    pub scope_depth: u8,
-    pub locals: Vec<Local<'a>>
+pub struct Compiler {
+    pub enclosing: Option<Box<Compiler>>,
+    pub scope_depth: usize,
+    pub locals: Vec<Local>,
+    pub upvalues: Vec<Upvalue>,
+
+    // the loops we're inside, innermost last
+    pub loops: Vec<Loop>,
+
+    // how many values the code emitted so far will have on the stack
+    // at this point. since we're an expression language, temporaries
+    // (operands to an op, say) can sit underneath a `let`'s locals,
+    // so a local's slot isn't just its index in `locals`
+    pub stack_depth: usize,
 }
 
 #[derive(Debug)]
-pub struct Local<'a> {
-    pub depth: u8,
-    pub name: &'a crate::scanner::Token
+pub struct Local {
+    pub depth: usize,
+    pub name: String,
+    pub slot: u8,
+
+    // an inner function closes over this local, so it has to be
+    // moved off the stack when its scope ends
+    pub is_captured: bool,
 }
 
+// where `recur` jumps back to. the loop's bindings are `count` locals
+// starting at `first_slot`, and `stack_depth` is what it was once they
+// were all on the stack
 #[derive(Debug)]
-pub struct ASTParser<'a> {
-    pub current: Rc<Option<crate::scanner::Token>>,
-    pub had_error: bool,
-    pub panic_mode: bool,
-    pub scanner: &'a mut crate::scanner::Scanner<'a>,
-    pub source: &'a str,
+#[derive(Clone)]
+pub struct Loop {
+    pub start: usize,
+    pub first_slot: usize,
+    pub count: usize,
+    pub stack_depth: usize,
+
+    // the loop's value is what its function returns
+    pub tail: bool,
+}
+
+// a variable from an enclosing function. if `is_local`, `index` is a
+// stack slot in the immediately enclosing function; otherwise it's an
+// index into that function's own upvalues
+#[derive(Debug)]
+pub struct Upvalue {
+    pub index: usize,
+    pub is_local: bool,
+}
+
+fn init_compiler() -> Compiler {
+    Compiler {
+        enclosing: None,
+        scope_depth: 0,
+        locals: Vec::new(),
+        upvalues: Vec::new(),
+        loops: Vec::new(),
+        stack_depth: 0,
+    }
 }
 
 
 fn begin_scope(compiler: &mut Compiler) {
     compiler.scope_depth += 1
 }
-fn end_scope(compiler: &mut Compiler) {
-    compiler.scope_depth -= 1
-}
 
-fn build_ast(mut parser: ASTParser,
-             ast: &mut Arena<Rc<Option<crate::scanner::Token>>>) -> NodeId {
+// drops the locals declared in the scope we're leaving, and returns
+// them (the caller needs to emit code to pop them)
+fn end_scope(compiler: &mut Compiler) -> Vec<Local> {
+    compiler.scope_depth -= 1;
 
-    let root = ast.new_node(Rc::new(Some(crate::scanner::Token{
-        typ: crate::scanner::TokenType::NOOP,
-        line: 0,
-        start: 0,
-        length: 0,
-        error: None})));
+    let mut popped = Vec::new();
+    while let Some(local) = compiler.locals.last() {
+        if local.depth <= compiler.scope_depth {
+            break;
+        }
+        popped.push(compiler.locals.pop().unwrap());
+    }
+    popped
+}
+
+// innermost binding wins, so walk backwards. returns the index into
+// `locals`, not the stack slot
+fn resolve_local(compiler: &Compiler, name: &str) -> Option<usize> {
+    compiler.locals.iter()
+        .rposition(|l| l.name == name)
+}
 
-    ast_advance(&mut parser);
+// whether something in tail position of the innermost loop (if
+// we're in one) is in tail position of the function too, so its value
+// is what the function returns
+fn function_tail(compiler: &Compiler, tail: bool) -> bool {
+    tail && compiler.loops.last().is_none_or(|l| l.tail)
+}
 
-    // because at the top level of a file there may be many expressions,
-    // we loop through them here until we hit the EOF. this is
-    // effectively a `do`
-    loop {
-        if &parser.current.as_ref().as_ref().unwrap().typ == &crate::scanner::TokenType::EOF {
-            break
+// look for `name` in the enclosing functions, threading an upvalue
+// through each function between here and there
+fn resolve_upvalue(compiler: &mut Compiler, name: &str) -> Option<usize> {
+    let (index, is_local) = {
+        let enclosing = compiler.enclosing.as_mut()?;
+        match resolve_local(enclosing, name) {
+            Some(i) => {
+                enclosing.locals[i].is_captured = true;
+                (enclosing.locals[i].slot as usize, true)
+            },
+            None => (resolve_upvalue(enclosing, name)?, false)
         }
+    };
 
-        // this does one s-expression
-        ast_expression(&mut parser, ast, root);
-        ast_advance(&mut parser);
-    }
+    Some(add_upvalue(compiler, index, is_local))
+}
 
-    root
+fn add_upvalue(compiler: &mut Compiler, index: usize, is_local: bool) -> usize {
+    // closing over the same variable twice shares the upvalue
+    match compiler.upvalues.iter()
+        .position(|u| u.index == index && u.is_local == is_local) {
+            Some(i) => i,
+            None => {
+                compiler.upvalues.push(Upvalue {
+                    index,
+                    is_local,
+                });
+                compiler.upvalues.len() - 1
+            }
+        }
 }
 
-fn ast_expression(parser: &mut ASTParser,
-                  ast: &mut indextree::Arena<Rc<Option<crate::scanner::Token>>>,
-                  parent: indextree::NodeId) {
+// the top level of the source is compiled into this, as though it
+// were the body of a function with no arguments
+pub fn compile_script(source: &str,
+                      file: &str,
+                      file_id: crate::scanner::FileId,
+                      interner: &mut crate::interner::Interner,
+                      globals: &mut crate::globals::Globals) -> Result<crate::value::Function, Box<crate::error::SophieError>> {
+    let file: Rc<str> = file.into();
+    let source: Rc<str> = source.into();
+    let mut function = crate::value::init_function(None,
+                                                   Rc::clone(&file),
+                                                   Rc::clone(&source));
+    compile(&source, &file, file_id, &mut function.chunk, interner, globals)?;
+    Ok(function)
+}
 
-    match parser.current.as_ref().as_ref().unwrap().typ {
 
-        crate::scanner::TokenType::LEFTPAREN => {
-            let current = Rc::clone(&parser.current);
-            let subtree = ast.new_node(current);
-            parent.append(subtree, ast);
+// take source, build a Chunk of bytecode. `file` and `source` are kept
+// by the functions we build so runtime errors can point at their code
+pub fn compile(source: &Rc<str>,
+               file: &Rc<str>,
+               file_id: crate::scanner::FileId,
+               chunk: &mut crate::chunk::Chunk,
+               interner: &mut crate::interner::Interner,
+               globals: &mut crate::globals::Globals) -> Result<(), Box<crate::error::SophieError>> {
 
-            loop {
-                ast_advance(parser);
-                if parser.current.as_ref().as_ref().unwrap().typ ==
-                    crate::scanner::TokenType::RIGHTPAREN {
-                        return;
-                    }
+    // code -> forms
+    let forms = crate::reader::read(source, file, file_id)?;
+    crate::validate::validate(&forms, file, source)?;
 
-                ast_expression(parser, ast, subtree);
-            }
+    let mut generator = init_generator(Rc::clone(file), Rc::clone(source), interner, globals);
 
-        },
+    // an empty program still has to leave something for OPRETURN
+    let mut last_span = crate::scanner::Span {
+        file_id,
+        start: 0,
+        end: 0,
+        line: 0,
+        col: 0,
+    };
+    if forms.is_empty() {
+        generator.emit_byte(chunk, &last_span, opcode!(OPNIL));
+    }
 
-        _ => {
-            let current = Rc::clone(&parser.current);
-            let elem = ast.new_node(current);
-            parent.append(elem, ast);
+    // if there's more than one form at the top level, we want to eval
+    // each in turn (presumably they side-effect), and throw away each
+    // form's value until the last one. so we tell the VM to pop into
+    // nothing. `do` does the same, in `body`
+    for (i, form) in forms.iter().enumerate() {
+        generator.expression(form, chunk);
+        if i + 1 < forms.len() {
+            generator.emit_pop(chunk, &form.span);
+            generator.compiler.stack_depth -= 1;
         }
+        last_span = form.span;
     }
-}
 
-pub fn ast_advance(parser: &mut ASTParser) {
-    loop {
-        parser.current = Rc::new(
-            Some(crate::scanner::scan_token(parser.scanner,
-                                            parser.source)));
+    generator.end_compiler(&last_span, chunk);
 
-        match &parser.current.as_ref().as_ref().unwrap().error {
-            Some(err) => {
-                let message = err.to_string();
-                ast_error_at_current(parser, message, parser.source)
-            },
-            None => break
-        }
+    match generator.error {
+        Some(err) => Err(err),
+        None => Ok(())
     }
 }
 
-fn ast_error_at_current(parser: &mut ASTParser,
-                        message: String,
-                        source: &str) {
-    if parser.panic_mode {
-        return;
+// the operators the VM does with a single instruction. their operands
+// are compiled first, then the opcode
+fn builtin_op(name: &str) -> Option<crate::chunk::Opcode> {
+    match name {
+        "+" => Some(crate::chunk::Opcode::OPADD),
+        "-" => Some(crate::chunk::Opcode::OPSUBTRACT),
+        "*" => Some(crate::chunk::Opcode::OPMULTIPLY),
+        "/" => Some(crate::chunk::Opcode::OPDIVIDE),
+        "not" => Some(crate::chunk::Opcode::OPNOT),
+        "=" => Some(crate::chunk::Opcode::OPEQUAL),
+        "<" => Some(crate::chunk::Opcode::OPLT),
+        ">" => Some(crate::chunk::Opcode::OPGT),
+        "<=" => Some(crate::chunk::Opcode::OPLTE),
+        ">=" => Some(crate::chunk::Opcode::OPGTE),
+        "len" => Some(crate::chunk::Opcode::OPLEN),
+        "print" => Some(crate::chunk::Opcode::OPPRINT),
+        _ => None
     }
-    parser.had_error = true;
-    parser.panic_mode = true;
-    ast_error_at(parser, message, source)
 }
 
-fn ast_error_at(parser: &mut ASTParser,
-                message: String,
-                source: &str) {
-    let token = parser.current.as_ref().as_ref().unwrap();
-
-    print!("[line {}] Error", token.line);
-    if token.typ == crate::scanner::TokenType::EOF {
-        print!(" at end");
-    } else if token.typ == crate::scanner::TokenType::ERROR {
-        print!("");
-    } else {
-        print!(" at '{}'",
-               &source[token.start..token.start+token.length]);
-    }
-    println!(": {}", message);
+// names that mean something by themselves, so can't be bound or used
+// as values
+pub fn is_reserved(name: &str) -> bool {
+    matches!(name,
+             "def" | "fn" | "if" | "let" | "and" | "or"
+             | "do" | "when" | "unless" | "cond" | "loop" | "recur")
+        || builtin_op(name).is_some()
 }
 
-// take source, build a Chunk of bytecode
-pub fn compile(source: &str,
-               mut chunk: &mut crate::chunk::Chunk) -> bool {
-
-    // code -> AST
-    let mut scanner = crate::scanner::init_scanner();
-    let ast_parser =  ASTParser{current: Rc::new(None),
-                                had_error: false,
-                                panic_mode: false,
-                                scanner: &mut scanner,
-                                source: source};
-
-    let mut ast = Arena::<Rc<Option<crate::scanner::Token>>>::new();
-    let root_id = build_ast(ast_parser, &mut ast);
-    let root = ast.get(root_id);
-
-    let mut generator = init_generator();
-
-    let mut child = root.unwrap().first_child();
-
-    loop {
-        match child {
-            None => break,
-            Some(n) => {
-                let node = ast.get(n).unwrap();
-                generator.expression(&ast, &node, &mut chunk, source);
-                child = node.next_sibling();
-
-                // tif there's more than one expression at the top of
-                // the AST, we want to eval each in turn (presumably
-                // they side-effect), and throw away each expression's
-                // value until the last one. so we tell the VM to pop
-                // into nothing.`do` will have to do similar.
-                // XXX also it's stupid how we pass a
-                // token all the way through to emit_* when all we
-                // really care about is the line number. fix this. and
-                // in this case (and `end_compiler`), just grab the
-                // value of the head of `chunk.lines` and use that
-                if child.is_some() {
-                    generator.emit_pop(&mut chunk,
-
-                                       // this token is a placeholder,
-                                       // see comment above
-                                       &crate::scanner::Token {
-                                           typ: crate::scanner::TokenType::NOOP,
-                                           line: 0,
-                                           start: 0,
-                                           length: 0,
-                                           error: None},
-                    )
+// works on the forms from the reader
+// pushes onto the bytecode in Chunk
+impl Generator<'_> {
+
+    // every expression leaves exactly one value on the stack
+    fn expression(&mut self,
+                  expr: &crate::reader::Expr,
+                  chunk: &mut crate::chunk::Chunk) {
+        self.expression_at(expr, chunk, false)
+    }
+
+    // `tail` if nothing is left to do after `expr` but finish the
+    // innermost loop (or function). that's the only place `recur` can
+    // go, and where a call can take over its caller's frame
+    fn expression_at(&mut self,
+                     expr: &crate::reader::Expr,
+                     chunk: &mut crate::chunk::Chunk,
+                     tail: bool) {
+
+        let base = self.compiler.stack_depth;
+
+        match &expr.kind {
+            crate::reader::ExprKind::LITERAL(literal) =>
+                self.literal(chunk, literal, &expr.span),
+            crate::reader::ExprKind::SYMBOL(name) =>
+                self.identifier(chunk, name, &expr.span),
+            crate::reader::ExprKind::QUOTE(quoted) =>
+                self.quote(chunk, quoted),
+            crate::reader::ExprKind::LIST(items) =>
+                self.list(chunk, items, tail),
+            crate::reader::ExprKind::KEYWORD(name) =>
+                self.keyword(chunk, name, &expr.span),
+            crate::reader::ExprKind::VECTOR(items) =>
+                self.collection(chunk, opcode!(OPVECTOR), items, &expr.span),
+            crate::reader::ExprKind::MAP(items) =>
+                self.collection(chunk, opcode!(OPMAP), items, &expr.span),
+            crate::reader::ExprKind::SET(items) =>
+                self.collection(chunk, opcode!(OPSET), items, &expr.span),
+        }
+
+        self.compiler.stack_depth = base + 1;
+    }
+
+    // special forms, builtin ops, and calls
+    fn list(&mut self,
+            chunk: &mut crate::chunk::Chunk,
+            items: &[crate::reader::Expr],
+            tail: bool) {
+        let (head, args) = items.split_first()
+            .expect("validate rejects empty lists");
+
+        if let crate::reader::ExprKind::SYMBOL(name) = &head.kind {
+            match name.as_str() {
+                "if" => return self.if_form(chunk, &head.span, args, tail),
+                "let" => return self.let_form(chunk, &head.span, args, tail, false),
+                "loop" => return self.let_form(chunk, &head.span, args, tail, true),
+                "recur" => return self.recur_form(chunk, &head.span, args, tail),
+                "fn" => return self.fn_form(chunk, &head.span, args),
+                "def" => return self.def_form(chunk, &head.span, args),
+                "do" => return self.body(chunk, &head.span, args, tail),
+                "when" => return self.when_form(chunk, &head.span, args, tail, false),
+                "unless" => return self.when_form(chunk, &head.span, args, tail, true),
+                "cond" => return self.cond_form(chunk, &head.span, args, tail),
+                "and" => return self.logical_form(chunk, &head.span, args, tail, opcode!(OPJUMPIFFALSE)),
+                "or" => return self.logical_form(chunk, &head.span, args, tail, opcode!(OPJUMPIFTRUE)),
+                _ => ()
+            }
+
+            if let Some(op) = builtin_op(name) {
+                return self.builtin(chunk, &head.span, name, op, args);
+            }
+        }
+
+        // a function call. unlike the builtin ops, the callee goes on
+        // the stack first, then its arguments
+        self.expression(head, chunk);
+        for arg in args.iter() {
+            self.expression(arg, chunk);
+        }
+
+        // only if the function returns straight away afterwards, not
+        // just the loop we're in
+        let op = if function_tail(&self.compiler, tail) {
+            opcode!(OPTAILCALL)
+        } else {
+            opcode!(OPCALL)
+        };
+
+        match u8::try_from(args.len()) {
+            Ok(argc) => self.emit_bytes(chunk,
+                                        &head.span,
+                                        op,
+                                        argc),
+            Err(_) => self.error(&head.span,
+                                 "Can't have more than 255 arguments.".to_string())
+        }
+    }
+
+    // operands, then the operator. the arithmetic ops take any number
+    // of operands and are folded into a chain of binary ops; the
+    // comparisons are told how many operands there are
+    fn builtin(&mut self,
+               chunk: &mut crate::chunk::Chunk,
+               span: &crate::scanner::Span,
+               name: &str,
+               op: crate::chunk::Opcode,
+               args: &[crate::reader::Expr]) {
+        let byte = crate::chunk::Opcode::to_u8(&op).unwrap();
+
+        match op {
+            crate::chunk::Opcode::OPADD |
+            crate::chunk::Opcode::OPMULTIPLY |
+            crate::chunk::Opcode::OPSUBTRACT |
+            crate::chunk::Opcode::OPDIVIDE => {
+                let base = self.compiler.stack_depth;
+                match (&op, args.len()) {
+                    // (+) is 0 and (*) is 1
+                    (crate::chunk::Opcode::OPADD, 0) =>
+                        self.emit_constant(chunk, span, crate::value::ConstantType::INT(0)),
+                    (crate::chunk::Opcode::OPMULTIPLY, 0) =>
+                        self.emit_constant(chunk, span, crate::value::ConstantType::INT(1)),
+                    (_, 0) => unreachable!("validate checks builtin arities"),
+
+                    // (- x) is -x, and (/ x) is 1.0/x, since the
+                    // reciprocal of an int is hardly ever an int
+                    (crate::chunk::Opcode::OPSUBTRACT, 1) => {
+                        self.expression(&args[0], chunk);
+                        self.emit_byte(chunk, span, opcode!(OPNEGATE));
+                    },
+                    (crate::chunk::Opcode::OPDIVIDE, 1) => {
+                        self.emit_constant(chunk, span, crate::value::ConstantType::FLOAT(1.0));
+                        self.compiler.stack_depth += 1;
+                        self.expression(&args[0], chunk);
+                        self.emit_byte(chunk, span, byte);
+                    },
+
+                    // (+ a b c) is (+ (+ a b) c)
+                    _ => {
+                        self.expression(&args[0], chunk);
+                        for arg in args[1..].iter() {
+                            self.expression(arg, chunk);
+                            self.emit_byte(chunk, span, byte);
+                            self.compiler.stack_depth = base + 1;
+                        }
+                    }
+                }
+            },
+
+            crate::chunk::Opcode::OPEQUAL |
+            crate::chunk::Opcode::OPLT |
+            crate::chunk::Opcode::OPGT |
+            crate::chunk::Opcode::OPLTE |
+            crate::chunk::Opcode::OPGTE => {
+                debug_assert!(!args.is_empty(), "validate checks builtin arities");
+                for arg in args.iter() {
+                    self.expression(arg, chunk);
+                }
+                match u8::try_from(args.len()) {
+                    Ok(n) => self.emit_bytes(chunk, span, byte, n),
+                    Err(_) => self.error(span,
+                                         format!("Can't compare more than 255 values with '{}'.", name))
+                }
+            },
+
+            _ => {
+                for arg in args.iter() {
+                    self.expression(arg, chunk);
                 }
+                self.emit_byte(chunk, span, byte);
             }
         }
     }
 
-    generator.end_compiler(&crate::scanner::Token {
-                               typ: crate::scanner::TokenType::NOOP,
-                               line: 0,
-                               start: 0,
-                               length: 0,
-                               error: None},
-                           &mut chunk);
+    // the elements, then `op` with a two byte count of them
+    fn collection(&mut self,
+                  chunk: &mut crate::chunk::Chunk,
+                  op: u8,
+                  items: &[crate::reader::Expr],
+                  span: &crate::scanner::Span) {
+        if items.len() > u16::MAX as usize {
+            self.error(span, "Too many elements in collection literal.".to_string());
+            return;
+        }
 
-    // !parser.had_error
+        for item in items.iter() {
+            self.expression(item, chunk);
+        }
 
-    true
-}
+        self.emit_byte(chunk, span, op);
+        self.emit_bytes(chunk,
+                        span,
+                        ((items.len() >> 8) & 0xff) as u8,
+                        (items.len() & 0xff) as u8);
+    }
 
-// rename from Action?
-type Action = fn(&mut Generator,
-                 &mut crate::chunk::Chunk,
-                 &crate::scanner::Token,
-                 &str);
-
-static token_fn: [Action; 51] = [
-    Generator::noop,
-
-    Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop, Generator::op, Generator::op,
-    Generator::noop, Generator::noop, Generator::op,
-
-    Generator::noop, Generator::op,
-    Generator::op, Generator::op,
-    Generator::op, Generator::op,
-    Generator::op, Generator::op,
-
-    // Literals
-    Generator::identifier, Generator::string,
-    Generator::float, Generator::int, Generator::noop,
-    Generator::literal, Generator::literal,
-    Generator::literal,
-
-    // Keywords
-    Generator::noop, Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop, Generator::noop,
-    Generator::noop, Generator::op, Generator::noop, Generator::noop,
-    Generator::noop, Generator::noop, Generator::noop,
-    Generator::noop, Generator::op, Generator::op,
-    Generator::op,
-
-    Generator::noop,
-    Generator::noop
-];
-
-// works on the AST
-// pushes onto the bytecode in Chunk
-impl Generator {
+    // (if cond then else?)
+    fn if_form(&mut self,
+               chunk: &mut crate::chunk::Chunk,
+               span: &crate::scanner::Span,
+               args: &[crate::reader::Expr],
+               tail: bool) {
+        debug_assert!(args.len() >= 2, "validate checks if shapes");
+        let base = self.compiler.stack_depth;
 
-    // we are given a node
-    fn expression(&mut self,
-                  ast: &Arena::<Rc<Option<crate::scanner::Token>>>,
-                  node: &Node::<Rc<Option<crate::scanner::Token>>>,
-                  mut chunk: &mut crate::chunk::Chunk,
-                  source: &str) {
+        // add the conditional code
+        self.expression(&args[0], chunk);
 
-        let t = node.get().as_ref();
+        // a placeholder offset. this will be patched once we know how
+        // long the `true` branch is
+        let then_jump = self.emit_jump(chunk, span, opcode!(OPJUMPIFFALSE));
 
-        match t {
+        // OPJUMPIFFALSE pops the conditional
+        self.compiler.stack_depth = base;
 
-            // a single token
+        self.expression_at(&args[1], chunk, tail);
 
-            // using match here isn't quire right, since it won't
-            // work with `((if true + -) 1 2)`. use an if.
-            Some(token)
-                if token.typ != crate::scanner::TokenType::LEFTPAREN =>
-                { self.emit_token(&mut chunk, token, source)},
+        // we want to jump to after the else branch. as above, we'll
+        // patch the offset once we know the length of the else branch
+        let else_jump = self.emit_jump(chunk, span, opcode!(OPJUMP));
 
-            // an S-expression
-            _ => {
-                let first_child = ast
-                    .get(node.first_child().unwrap())
-                    .unwrap();
-
-                // handle nonstandard forms
-                match first_child.get().as_ref() {
-                    Some(n) if n.typ == crate::scanner::TokenType::IF => {
-                        // conditional AST node
-                        let mut conditional_id = first_child
-                            .next_sibling().unwrap();
-                        let conditional_node = ast.get(conditional_id).unwrap();
-
-                        // `true` branch AST node
-                        let true_id = conditional_node.next_sibling().unwrap();
-                        let true_node = ast.get(true_id).unwrap();
-
-                        // add the conditional code
-                        self.expression(ast, conditional_node,
-                                        &mut chunk, source);
-
-                        // add `if` opcode
-                        self.emit_byte(chunk,
-                                       t.as_ref().unwrap(),
-                                       crate::chunk::Opcode::to_u8(&crate::chunk::Opcode::OPJMPIFFALSE).unwrap());
-
-                        // placeholder for jmp location. this will be patched
-                        // and should be two bytes at least XXX
-                        self.emit_byte(chunk, t.as_ref().unwrap(), 0);
-                        let patch_loc = chunk.code.len();
-
-                        // add the `true` branch
-                        self.expression(ast, true_node,
-                                        &mut chunk, source);
-
-                        // we want to jump to after the else branch so
-                        // emit a JMP here, and a placeholder
-                        // location. we'll patch this after we know
-                        // the length of the else branch. as above
-                        // this should be longer than a single byte
-                        self.emit_byte(chunk,
-                                       t.as_ref().unwrap(),
-                                       crate::chunk::Opcode::to_u8(&crate::chunk::Opcode::OPJMP).unwrap());
-                        self.emit_byte(chunk, t.as_ref().unwrap(), 0);
-                        let else_patch_loc = chunk.code.len();
-
-                        // now go back and patch the jmpif location
-                        // (this is where we end up if the cond fails-
-                        // just in front of the else branch)
-                        chunk.code[patch_loc-1] = chunk.code.len() as u8;
-
-                        match true_node.next_sibling() {
-                            Some(id) => {
-                                let else_node = ast.get(id).unwrap();
-                                self.expression(ast, else_node,
-                                                &mut chunk, source);
-                            }
-                            None => {
-                                // no else clause, but we need to push
-                                // a value regardless
-                                self.emit_byte(chunk,
-                                               t.as_ref().unwrap(),
-                                               crate::chunk::Opcode::to_u8(&crate::chunk::Opcode::OPNIL).unwrap())
-                            }
-                        }
+        // this is where we end up if the cond fails, just in front of
+        // the else branch
+        self.patch_jump(chunk, span, then_jump);
 
-                        // go back and patch the jmp before the else
-                        chunk.code[else_patch_loc-1] = chunk.code.len() as u8;
+        // only one of the branches runs
+        self.compiler.stack_depth = base;
 
+        match args.get(2) {
+            Some(else_branch) => self.expression_at(else_branch, chunk, tail),
 
-                    },
-                    Some(n) if n.typ == crate::scanner::TokenType::LET => {
-                        // (let [a (+ 1 2) b 7] (+ a b))
-                        // at this point, the AST does not know
-                        // anything about lists or vecs, so we march
-                        // symbolwise through `let` bindings
-                        // XXX probably we should parse lists, vecs,
-                        // and dicts into the AST.
-
-                        // first read the open bracket
-                        let mut next_id = first_child.next_sibling().unwrap();
-                        let mut next_node = ast.get(next_id).unwrap();
-                        // XXX assert that was actually a bracket
-
-                        // work through the bindings
-                        next_id = next_node.next_sibling().unwrap();
-                        next_node = ast.get(next_id).unwrap();
-                        let mut i = 0;
-                        loop {
-                            // first element of binding form, symbol
-                            // (or close square bracket)
-                            let t = node.get().as_ref();
-                            match t {
-                                Some(t) if t.typ == crate::scanner::TokenType::RIGHTBRACKET => break,
-                                Some(t) if t.typ == crate::scanner::TokenType::IDENTIFIER => {
-
-                                    // good, it's a symbol
-                                    // search locals for symbol. if it's there at current depth, error (can't rebind).
-                                    // if it's not, append to locals list. take
-                                    break;
-                                }
-
-                            }
-                        }
-                    },
-                    Some(n) if n.typ == crate::scanner::TokenType::DEF => {
-                        // ok it's a def. so we expect an identifier
-                        // next.
-                        // `identifier` is a scanner::Token (and
-                        // should be of type IDENTIFIER)
-                        let sym_id = first_child.next_sibling().unwrap();
-                        let sym_node = ast.get(sym_id).unwrap();
-
-                        let val_id = sym_node.next_sibling().unwrap();
-                        let val_node = ast.get(val_id).unwrap();
-
-                        // this is a scanner::Token
-                        let symbol = sym_node
-                            .get().as_ref().as_ref().unwrap();
-                        let start = symbol.start;
-                        let len = symbol.length;
-                        let s = source[start..start+len].to_owned();
-
-                        let ct = crate::value::ConstantType::SYMBOL(s);
-
-                        // emit the constant representing the symbol
-                        // (name) (and not op_constant!)
-
-                        // this just puts the constant in the constant
-                        // table and returns its index. does not add a
-                        // bytecode
-                        let ix = self.make_constant(&mut chunk,
-                                                    ct);
-
-                        // the VM will replace the symbol's name with
-                        // the symbol's constant index on the stack,
-                        // since op_def operates on that
-                        self.emit_bytes(&mut chunk,
-                                        symbol,
-                                        opcode!(OPDEFSYM),
-                                        ix);
-
-                        // emit the value
-                        self.expression(ast, val_node,
-                                        &mut chunk, source);
-
-                        // emit the OP_DEF
-                        self.expression(ast, first_child,
-                                        &mut chunk, source);
-                    }
-                    _ => {
+            // no else clause, but we need to push a value regardless
+            None => self.emit_byte(chunk, span, opcode!(OPNIL))
+        }
 
+        self.patch_jump(chunk, span, else_jump);
+    }
 
-                        // otherwise assume we're in a prefix expression
-                        // we generate operands, then operator
-                        let mut next_child = first_child.next_sibling();
-
-                        // catch semantic problems (arity, types?)
-                        // example: `(def n)` vs `(def n 1)`
-                        // should the first form default to nil?
-                        // in thast case, this is probably the place to do it
-                        // we'd need logic here to say "is `first` == DEF? if
-                        // so, if `rest` is len 0, then emil a nil here. if
-                        // `resst` is len 1, then emit its element. if `rest`
-                        // is len > 1, it's an error"
-                        // ~or~ we go fully variadic and emit the operand count
-                        // here. or both- we'd have a table of op->arity:
-                        //   + -> variadic
-                        //   def -> [0,1]
-                        //   not -> 1
-                        // then here we know to check those bounds,
-                        // and also if we should emit the argument count
-                        // for now we say `def` will only every have 1 arg
-                        loop {
-                            match next_child {
-                                None => break,
-                                Some(n) => {
-                                    let node = ast.get(n).unwrap();
-                                    self.expression(ast,
-                                                    &node,
-                                                    &mut chunk,
-                                                    source);
-                                    next_child = node.next_sibling();
-                                }
-                            }
-                        }
+    // (when cond body...), or (unless cond body...) if `negate`. nil
+    // if the body doesn't run
+    fn when_form(&mut self,
+                 chunk: &mut crate::chunk::Chunk,
+                 span: &crate::scanner::Span,
+                 args: &[crate::reader::Expr],
+                 tail: bool,
+                 negate: bool) {
+        debug_assert!(!args.is_empty(), "validate checks when shapes");
+        let test = &args[0];
+        let base = self.compiler.stack_depth;
+
+        self.expression(test, chunk);
+        if negate {
+            self.emit_byte(chunk, span, opcode!(OPNOT));
+        }
+        let skip_jump = self.emit_jump(chunk, span, opcode!(OPJUMPIFFALSE));
+        self.compiler.stack_depth = base;
 
-                        // if our VM is to support variadic ops, we'd want
-                        // to push the count of operands here
+        self.body(chunk, span, &args[1..], tail);
+        let end_jump = self.emit_jump(chunk, span, opcode!(OPJUMP));
 
+        self.patch_jump(chunk, span, skip_jump);
+        self.compiler.stack_depth = base;
+        self.emit_byte(chunk, span, opcode!(OPNIL));
 
-                        // finally, push on the operator
-                        self.expression(ast, first_child, &mut chunk, source);
-                    }
-                }
+        self.patch_jump(chunk, span, end_jump);
+    }
+
+    // (cond test expr test expr ... :else expr). the first test that
+    // passes picks the value; nil if none do
+    fn cond_form(&mut self,
+                 chunk: &mut crate::chunk::Chunk,
+                 span: &crate::scanner::Span,
+                 args: &[crate::reader::Expr],
+                 tail: bool) {
+        debug_assert!(args.len().is_multiple_of(2), "validate checks cond shapes");
+        let base = self.compiler.stack_depth;
+
+        // every clause that runs jumps from the end of its expression
+        // to the end of the cond
+        let mut end_jumps = Vec::new();
+        let mut has_else = false;
+
+        for clause in args.chunks(2) {
+            let (test, expr) = (&clause[0], &clause[1]);
+
+            // always passes, so there's no test to compile and nothing
+            // after it can run
+            if matches!(&test.kind, crate::reader::ExprKind::KEYWORD(k) if k == "else") {
+                self.expression_at(expr, chunk, tail);
+                has_else = true;
+                break;
             }
 
+            self.expression(test, chunk);
+            let next_jump = self.emit_jump(chunk, &test.span, opcode!(OPJUMPIFFALSE));
+            self.compiler.stack_depth = base;
+
+            self.expression_at(expr, chunk, tail);
+            end_jumps.push(self.emit_jump(chunk, &expr.span, opcode!(OPJUMP)));
+
+            self.patch_jump(chunk, &test.span, next_jump);
+            self.compiler.stack_depth = base;
+        }
+
+        if !has_else {
+            self.emit_byte(chunk, span, opcode!(OPNIL));
         }
-    }
 
-    fn emit_token(&mut self,
-                  mut chunk: &mut crate::chunk::Chunk,
-                  token: &crate::scanner::Token,
-                  source: &str) {
+        for jump in end_jumps {
+            self.patch_jump(chunk, span, jump);
+        }
+    }
 
-        let f = token_fn[crate::scanner::TokenType::to_u8(&token.typ).unwrap() as usize];
+    // (and a b ...) or (or a b ...), depending on whether `jump` is
+    // OPJUMPIFFALSE or OPJUMPIFTRUE. the first value that decides the
+    // answer is the answer, and nothing after it is evaluated
+    fn logical_form(&mut self,
+                    chunk: &mut crate::chunk::Chunk,
+                    span: &crate::scanner::Span,
+                    args: &[crate::reader::Expr],
+                    tail: bool,
+                    jump: u8) {
+        if args.is_empty() {
+            // (and) is true and (or) is nil, as nothing decided them
+            let empty = if jump == opcode!(OPJUMPIFFALSE) {
+                opcode!(OPTRUE)
+            } else {
+                opcode!(OPNIL)
+            };
+            self.emit_byte(chunk, span, empty);
+            return;
+        }
+        let base = self.compiler.stack_depth;
+
+        // the jumps pop what they test, so each value but the last is
+        // duplicated first. if we jump, the copy is the answer;
+        // otherwise it's popped and we try the next one
+        let mut end_jumps = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            if i + 1 == args.len() {
+                // the last one is the answer if we get that far
+                self.expression_at(arg, chunk, tail);
+                break;
+            }
+            self.expression(arg, chunk);
 
-        f(self, &mut chunk, &token, source);
+            self.emit_byte(chunk, &arg.span, opcode!(OPDUP));
+            end_jumps.push(self.emit_jump(chunk, &arg.span, jump));
+            self.emit_pop(chunk, &arg.span);
+            self.compiler.stack_depth = base;
+        }
 
+        for end_jump in end_jumps {
+            self.patch_jump(chunk, span, end_jump);
+        }
     }
 
-    fn literal(&mut self,
-               chunk: &mut crate::chunk::Chunk,
-               token: &crate::scanner::Token,
-               _source: &str) {
-        let byte = match token.typ {
-            crate::scanner::TokenType::NIL =>
-                crate::chunk::Opcode::OPNIL,
-            crate::scanner::TokenType::TRUE =>
-                crate::chunk::Opcode::OPTRUE,
-            crate::scanner::TokenType::FALSE =>
-                crate::chunk::Opcode::OPFALSE,
-            _ =>
-                return
-        };
-        self.emit_byte(chunk,
-                       token,
-                       crate::chunk::Opcode::to_u8(&byte).unwrap())
-    }
-
-    fn op(&mut self,
-          chunk: &mut crate::chunk::Chunk,
-          token: &crate::scanner::Token,
-          _source: &str) {
-
-        let byte = match token.typ {
-            crate::scanner::TokenType::PLUS =>
-                crate::chunk::Opcode::OPADD,
-            crate::scanner::TokenType::MINUS =>
-                crate::chunk::Opcode::OPSUBTRACT,
-            crate::scanner::TokenType::STAR =>
-                crate::chunk::Opcode::OPMULTIPLY,
-            crate::scanner::TokenType::SLASH =>
-                crate::chunk::Opcode::OPDIVIDE,
-            crate::scanner::TokenType::NOT =>
-                crate::chunk::Opcode::OPNOT,
-            crate::scanner::TokenType::EQUAL =>
-                crate::chunk::Opcode::OPEQUAL,
-            crate::scanner::TokenType::LESS =>
-                crate::chunk::Opcode::OPLT,
-            crate::scanner::TokenType::GREATER =>
-                crate::chunk::Opcode::OPGT,
-            crate::scanner::TokenType::LESSEQUAL =>
-                crate::chunk::Opcode::OPLTE,
-            crate::scanner::TokenType::GREATEREQUAL =>
-                crate::chunk::Opcode::OPGTE,
-            crate::scanner::TokenType::LEN =>
-                crate::chunk::Opcode::OPLEN,
-            crate::scanner::TokenType::PRINT =>
-                crate::chunk::Opcode::OPPRINT,
-            crate::scanner::TokenType::DEF =>
-                crate::chunk::Opcode::OPDEF,
-
-            _ =>
-                return
+    // (let [a (+ 1 2) b 7] (+ a b)), or (loop [...] ...) if
+    // `looping`, which is a let that `recur` can jump back to the top
+    // of with new values for the bindings
+    fn let_form(&mut self,
+                chunk: &mut crate::chunk::Chunk,
+                span: &crate::scanner::Span,
+                args: &[crate::reader::Expr],
+                tail: bool,
+                looping: bool) {
+        let bindings = match &args[0].kind {
+            crate::reader::ExprKind::VECTOR(bindings) => bindings,
+            _ => unreachable!("validate checks let shapes")
         };
+        let first_slot = self.compiler.stack_depth;
+
+        begin_scope(&mut self.compiler);
+
+        // work through the bindings. each value is compiled before
+        // its name is declared, so `(let [a (+ a 1)] a)` sees any
+        // outer `a`
+        for pair in bindings.chunks(2) {
+            let (name, value) = match (&pair[0].kind, pair.get(1)) {
+                (crate::reader::ExprKind::SYMBOL(name), Some(value)) => (name, value),
+                _ => unreachable!("validate checks let shapes")
+            };
+
+            // the value ends up on top of the stack, and that's where
+            // the local lives
+            let slot = self.compiler.stack_depth;
+            self.expression(value, chunk);
+            self.add_local(name, &pair[0].span, slot);
+        }
 
-        self.emit_byte(chunk,
-                       token,
-                       crate::chunk::Opcode::to_u8(&byte).unwrap())
-    }
+        if looping {
+            self.compiler.loops.push(Loop {
+                start: chunk.code.len(),
+                first_slot,
+                count: bindings.len() / 2,
+                stack_depth: self.compiler.stack_depth,
+                tail: function_tail(&self.compiler, tail),
+            });
+
+            // the body is the whole of the loop, so its end is the
+            // loop's tail whether or not the loop is in tail position
+            self.body(chunk, span, &args[1..], true);
+            self.compiler.loops.pop();
+        } else {
+            self.body(chunk, span, &args[1..], tail);
+        }
 
+        // the body's value is on top of the locals. copy it down into
+        // the first local's slot, then pop everything above that
+        let first_slot = self.compiler.locals.iter()
+            .rev()
+            .take_while(|l| l.depth == self.compiler.scope_depth)
+            .last()
+            .map(|l| l.slot);
+
+        let popped = end_scope(&mut self.compiler);
+        if let Some(slot) = first_slot {
+            // anything a closure captured has to be hoisted off the
+            // stack before we clobber it
+            let captured = popped.iter()
+                .filter(|l| l.is_captured)
+                .map(|l| l.slot)
+                .min();
+            if let Some(captured) = captured {
+                self.emit_bytes(chunk, span, opcode!(OPCLOSEUPVALUE), captured);
+            }
 
-    fn emit_byte(&mut self,
-                 chunk: &mut crate::chunk::Chunk,
-                 token: &crate::scanner::Token,
-                 byte: u8) {
-        chunk.write_chunk(byte, token.line);
+            self.emit_bytes(chunk, span, opcode!(OPSETLOCAL), slot);
+            for _ in 0..popped.len() {
+                self.emit_pop(chunk, span);
+            }
+        }
     }
 
-    fn emit_bytes(&mut self,
-                  mut chunk: &mut crate::chunk::Chunk,
-                  token: &crate::scanner::Token,
-                  byte0: u8,
-                  byte1: u8) {
-        self.emit_byte(&mut chunk, token, byte0);
-        self.emit_byte(&mut chunk, token, byte1)
+    // (recur a b ...) gives the innermost loop's bindings new values
+    // and jumps back to the top of its body
+    fn recur_form(&mut self,
+                  chunk: &mut crate::chunk::Chunk,
+                  span: &crate::scanner::Span,
+                  args: &[crate::reader::Expr],
+                  tail: bool) {
+        let target = match self.compiler.loops.last() {
+            Some(target) => target.clone(),
+            None => {
+                self.error(span, "Can't recur outside of a loop.".to_string());
+                return;
+            }
+        };
+        if !tail {
+            self.error_with_help(span,
+                                 "Can only recur from tail position.".to_string(),
+                                 "nothing in the loop can be left to do after a recur");
+            return;
+        }
+        if args.len() != target.count {
+            let bindings = if target.count == 1 { "binding" } else { "bindings" };
+            self.error(span,
+                       format!("'recur' expects a value for each of its loop's {} {}, got {}.",
+                               target.count,
+                               bindings,
+                               args.len()));
+            return;
+        }
+        let depth = self.compiler.stack_depth;
+
+        // work out all the new values before rebinding any of them,
+        // so (recur b a) swaps
+        for arg in args.iter() {
+            self.expression(arg, chunk);
+        }
+
+        // closures made this time round keep the values they saw
+        let captured = self.compiler.locals.iter()
+            .any(|l| l.is_captured && l.slot as usize >= target.first_slot);
+        if captured {
+            self.emit_bytes(chunk, span, opcode!(OPCLOSEUPVALUE), target.first_slot as u8);
+        }
+
+        for i in (0..target.count).rev() {
+            self.emit_bytes(chunk, span, opcode!(OPSETLOCAL), (target.first_slot + i) as u8);
+            self.emit_pop(chunk, span);
+        }
+
+        // and anything a `let` inside the loop put on top of its
+        // bindings
+        for _ in target.stack_depth..depth {
+            self.emit_pop(chunk, span);
+        }
+
+        self.emit_loop(chunk, span, target.start);
     }
 
-    fn end_compiler(&mut self,
-                    token: &crate::scanner::Token,
-                    mut chunk: &mut crate::chunk::Chunk) {
-        self.emit_return(&mut chunk, token)
+    // (fn [x y] (* x y))
+    // the body gets compiled into its own chunk, which lives in a
+    // Function, which lives in our constant table
+    fn fn_form(&mut self,
+               chunk: &mut crate::chunk::Chunk,
+               span: &crate::scanner::Span,
+               args: &[crate::reader::Expr]) {
+        let params = match &args[0].kind {
+            crate::reader::ExprKind::VECTOR(params) => params,
+            _ => unreachable!("validate checks fn shapes")
+        };
+
+        let mut function = crate::value::init_function(self.fn_name.take(),
+                                                       Rc::clone(&self.file),
+                                                       Rc::clone(&self.source));
+
+        // a fresh compiler for the function body. the callee itself
+        // sits in slot 0, arguments follow it
+        let enclosing = std::mem::replace(&mut self.compiler,
+                                          init_compiler());
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        self.compiler.stack_depth = 1;
+        begin_scope(&mut self.compiler);
+
+        for param in params.iter() {
+            match &param.kind {
+                crate::reader::ExprKind::SYMBOL(name) => {
+                    debug_assert!(function.arity < u8::MAX, "validate checks fn shapes");
+                    function.arity += 1;
+                    let slot = self.compiler.stack_depth;
+                    self.add_local(name, &param.span, slot);
+                    self.compiler.stack_depth += 1;
+                },
+                _ => unreachable!("validate checks fn shapes")
+            }
+        }
+
+        self.body(&mut function.chunk, span, &args[1..], true);
+
+        // OPRETURN throws away the whole frame, so there's no need to
+        // end the scope
+        self.emit_return(&mut function.chunk, span);
+
+        let enclosing = self.compiler.enclosing.take().unwrap();
+        let compiler = std::mem::replace(&mut self.compiler,
+                                         *enclosing);
+
+        if compiler.upvalues.len() > u8::MAX as usize {
+            self.error(span,
+                       "Too many closure variables in function.".to_string());
+            return;
+        }
+        function.upvalue_count = compiler.upvalues.len() as u8;
+
+        // OPCLOSURE is followed by a pair of bytes for each upvalue,
+        // telling the VM where to capture it from
+        let ix = self.make_constant(chunk,
+                                    span,
+                                    crate::value::ConstantType::FUNCTION(Rc::new(function)));
+        self.emit_indexed(chunk,
+                          span,
+                          opcode!(OPCLOSURE),
+                          opcode!(OPCLOSURELONG),
+                          ix);
+
+        for upvalue in compiler.upvalues.iter() {
+            match u8::try_from(upvalue.index) {
+                Ok(index) => self.emit_bytes(chunk,
+                                             span,
+                                             upvalue.is_local as u8,
+                                             index),
+                Err(_) => self.error(span,
+                                     "Too many closure variables in function.".to_string())
+            }
+        }
     }
 
-    fn emit_return(&mut self,
-                   mut chunk: &mut crate::chunk::Chunk,
-                   token: &crate::scanner::Token) {
-        self.emit_byte(&mut chunk,
-                       token,
-                       opcode!(OPRETURN))
+    // (def name value)
+    fn def_form(&mut self,
+                chunk: &mut crate::chunk::Chunk,
+                span: &crate::scanner::Span,
+                args: &[crate::reader::Expr]) {
+        let (name, name_span) = match &args[0] {
+            crate::reader::Expr {
+                kind: crate::reader::ExprKind::SYMBOL(name),
+                span: name_span
+            } => (name, name_span),
+            _ => unreachable!("validate checks def shapes")
+        };
+        let value = &args[1];
+
+        let slot = self.global_slot(name, name_span);
+
+        // `(def sq (fn ...))` names the function
+        if let crate::reader::ExprKind::LIST(items) = &value.kind {
+            if let Some(crate::reader::ExprKind::SYMBOL(head)) = items.first().map(|i| &i.kind) {
+                if head == "fn" {
+                    self.fn_name = Some(name.to_owned());
+                }
+            }
+        }
+
+        self.expression(value, chunk);
+        self.emit_indexed(chunk,
+                          span,
+                          opcode!(OPDEFINEGLOBAL),
+                          opcode!(OPDEFINEGLOBALLONG),
+                          slot);
     }
 
-    fn emit_pop(&mut self,
-                mut chunk: &mut crate::chunk::Chunk,
-                token: &crate::scanner::Token) {
-        self.emit_byte(&mut chunk,
-                       token,
-                       opcode!(OPPOP))
+    // an implicit `do`: each form in turn, keeping only the last
+    // one's value. nil if there are none
+    fn body(&mut self,
+            chunk: &mut crate::chunk::Chunk,
+            span: &crate::scanner::Span,
+            forms: &[crate::reader::Expr],
+            tail: bool) {
+        if forms.is_empty() {
+            self.emit_byte(chunk, span, opcode!(OPNIL));
+            self.compiler.stack_depth += 1;
+            return;
+        }
+
+        for (i, form) in forms.iter().enumerate() {
+            if i + 1 == forms.len() {
+                self.expression_at(form, chunk, tail);
+                break;
+            }
+            self.expression(form, chunk);
+            self.emit_pop(chunk, &form.span);
+            self.compiler.stack_depth -= 1;
+        }
     }
 
+    fn add_local(&mut self,
+                 name: &str,
+                 span: &crate::scanner::Span,
+                 slot: usize) {
+        debug_assert!(!is_reserved(name), "validate checks binding names");
+
+        let slot = match u8::try_from(slot) {
+            Ok(s) => s,
+            Err(_) => {
+                self.error(span,
+                           "Too many values on the stack.".to_string());
+                return;
+            }
+        };
 
-    fn noop(&mut self,
-            mut _chunk: &mut crate::chunk::Chunk,
-            token: &crate::scanner::Token,
-            _source: &str) {
-        println!("Strangely, I am in NOOP with token {:?}", token);
-        ()
+        self.compiler.locals.push(Local {
+            depth: self.compiler.scope_depth,
+            name: name.to_owned(),
+            slot,
+            is_captured: false,
+        });
     }
 
-    fn float(&mut self,
-              mut chunk: &mut crate::chunk::Chunk,
-              token: &crate::scanner::Token,
-              source: &str) {
-        let start = token.start;
-        let len = token.length;
-        let d = f64::from_str(&source[start..start+len]).unwrap();
-        let ct = crate::value::ConstantType::FLOAT(d);
+    fn literal(&mut self,
+               chunk: &mut crate::chunk::Chunk,
+               literal: &crate::reader::Literal,
+               span: &crate::scanner::Span) {
+        let ct = match literal {
+            crate::reader::Literal::NIL =>
+                return self.emit_byte(chunk, span, opcode!(OPNIL)),
+            crate::reader::Literal::BOOL(true) =>
+                return self.emit_byte(chunk, span, opcode!(OPTRUE)),
+            crate::reader::Literal::BOOL(false) =>
+                return self.emit_byte(chunk, span, opcode!(OPFALSE)),
+            crate::reader::Literal::INT(n) =>
+                crate::value::ConstantType::INT(*n),
+            crate::reader::Literal::FLOAT(n) =>
+                crate::value::ConstantType::FLOAT(*n),
+            crate::reader::Literal::STRING(s) =>
+                crate::value::ConstantType::STRING(self.interner.intern(s)),
+        };
 
-        self.emit_constant(&mut chunk,
-                           token,
-                           ct)
+        self.emit_constant(chunk, span, ct)
     }
 
-    fn int(&mut self,
-             mut chunk: &mut crate::chunk::Chunk,
-             token: &crate::scanner::Token,
-             source: &str) {
-        let start = token.start;
-        let len = token.length;
-        let d = i64::from_str(&source[start..start+len]).unwrap();
-        let ct = crate::value::ConstantType::INT(d);
+    // keywords evaluate to themselves
+    fn keyword(&mut self,
+               chunk: &mut crate::chunk::Chunk,
+               name: &str,
+               span: &crate::scanner::Span) {
+        let keyword = self.interner.intern(name);
+        self.emit_constant(chunk, span, crate::value::ConstantType::KEYWORD(keyword))
+    }
 
-        self.emit_constant(&mut chunk,
-                           token,
-                           ct)
+    // 'x is the symbol x itself, rather than what it's bound to
+    fn quote(&mut self,
+             chunk: &mut crate::chunk::Chunk,
+             quoted: &crate::reader::Expr) {
+        match &quoted.kind {
+            crate::reader::ExprKind::SYMBOL(name) => {
+                let symbol = self.interner.intern(name);
+                self.emit_constant(chunk,
+                                   &quoted.span,
+                                   crate::value::ConstantType::SYMBOL(symbol))
+            },
+            crate::reader::ExprKind::LITERAL(literal) =>
+                self.literal(chunk, literal, &quoted.span),
+            crate::reader::ExprKind::KEYWORD(name) =>
+                self.keyword(chunk, name, &quoted.span),
+            _ => self.error(&quoted.span,
+                            "Only symbols, keywords and literals can be quoted for now.".to_string())
+        }
     }
 
-    fn string(&mut self,
-              mut chunk: &mut crate::chunk::Chunk,
-              token: &crate::scanner::Token,
-              source: &str) {
+    fn identifier(&mut self,
+                  chunk: &mut crate::chunk::Chunk,
+                  name: &str,
+                  span: &crate::scanner::Span) {
 
-        let start = token.start;
-        let len = token.length;
-        // avoid "'s by dropping first and last char...
-        let s = source[start+1..start+len-1].to_owned();
+        if is_reserved(name) {
+            self.error(span, format!("'{}' can't be used as a value.", name));
+            return;
+        }
 
-        // let sv = string_val!(&ct);
-        let ct = crate::value::ConstantType::STRING(s);
+        if let Some(i) = resolve_local(&self.compiler, name) {
+            let slot = self.compiler.locals[i].slot;
+            self.emit_bytes(chunk,
+                            span,
+                            opcode!(OPGETLOCAL),
+                            slot);
+            return;
+        }
 
-        self.emit_constant(&mut chunk,
-                           token,
-                           ct)
+        if let Some(i) = resolve_upvalue(&mut self.compiler, name) {
+            match u8::try_from(i) {
+                Ok(i) => self.emit_bytes(chunk,
+                                         span,
+                                         opcode!(OPGETUPVALUE),
+                                         i),
+                Err(_) => self.error(span,
+                                     "Too many closure variables in function.".to_string())
+            }
+            return;
+        }
+
+        let slot = self.global_slot(name, span);
+        self.emit_indexed(chunk,
+                          span,
+                          opcode!(OPGETGLOBAL),
+                          opcode!(OPGETGLOBALLONG),
+                          slot)
     }
 
-    fn identifier(&mut self,
-              mut chunk: &mut crate::chunk::Chunk,
-              token: &crate::scanner::Token,
-              source: &str) {
+    fn emit_byte(&mut self,
+                 chunk: &mut crate::chunk::Chunk,
+                 span: &crate::scanner::Span,
+                 byte: u8) {
+        chunk.write_chunk(byte, span);
+    }
 
-        let start = token.start;
-        let len = token.length;
-        let s = source[start..start+len].to_owned();
+    fn emit_bytes(&mut self,
+                  chunk: &mut crate::chunk::Chunk,
+                  span: &crate::scanner::Span,
+                  byte0: u8,
+                  byte1: u8) {
+        self.emit_byte(chunk, span, byte0);
+        self.emit_byte(chunk, span, byte1)
+    }
 
-        let ct = crate::value::ConstantType::SYMBOL(s);
+    fn end_compiler(&mut self,
+                    span: &crate::scanner::Span,
+                    chunk: &mut crate::chunk::Chunk) {
+        self.emit_return(chunk, span)
+    }
 
-        let constant_ix = self.make_constant(&mut chunk,
-                                             ct);
+    fn emit_return(&mut self,
+                   chunk: &mut crate::chunk::Chunk,
+                   span: &crate::scanner::Span) {
+        self.emit_byte(chunk,
+                       span,
+                       opcode!(OPRETURN))
+    }
 
-        self.emit_bytes(&mut chunk,
-                        token,
-                        opcode!(OPSYM),
-                        constant_ix)
+    fn emit_pop(&mut self,
+                chunk: &mut crate::chunk::Chunk,
+                span: &crate::scanner::Span) {
+        self.emit_byte(chunk,
+                       span,
+                       opcode!(OPPOP))
     }
 
     fn emit_constant(&mut self,
-                     mut chunk: &mut crate::chunk::Chunk,
-                     token: &crate::scanner::Token,
+                     chunk: &mut crate::chunk::Chunk,
+                     span: &crate::scanner::Span,
                      val: crate::value::ConstantType) {
 
         // moves val to chunk
-        let constant_ix = self.make_constant(&mut chunk,
+        let constant_ix = self.make_constant(chunk,
+                                             span,
                                              val);
 
-        self.emit_bytes(&mut chunk,
-                        token,
-                        opcode!(OPCONSTANT),
-                        constant_ix)
+        self.emit_indexed(chunk,
+                          span,
+                          opcode!(OPCONSTANT),
+                          opcode!(OPCONSTANTLONG),
+                          constant_ix)
 
     }
 
-    fn make_constant(&self,
+    fn make_constant(&mut self,
                      chunk: &mut crate::chunk::Chunk,
-                     val: crate::value::ConstantType) -> u8 {
+                     span: &crate::scanner::Span,
+                     val: crate::value::ConstantType) -> usize {
 
         let id = chunk.add_constant(val);
-        u8::try_from(id).unwrap()
+        if id >= MAX_CONSTANTS {
+            self.error(span,
+                       "Too many constants in one chunk.".to_string());
+            return 0;
+        }
+        id
+    }
+
+    // where the global `name` lives, whether or not it's been defined
+    // yet; a function can refer to a global defined after it
+    fn global_slot(&mut self,
+                   name: &str,
+                   span: &crate::scanner::Span) -> usize {
+        let name = self.interner.intern(name);
+        let slot = self.globals.slot(&name);
+        if slot >= MAX_GLOBALS {
+            self.error(span,
+                       "Too many globals.".to_string());
+            return 0;
+        }
+        slot
+    }
+
+    // `op` followed by a one byte constant index if it fits, otherwise
+    // `long_op` followed by three (high byte first)
+    fn emit_indexed(&mut self,
+                    chunk: &mut crate::chunk::Chunk,
+                    span: &crate::scanner::Span,
+                    op: u8,
+                    long_op: u8,
+                    ix: usize) {
+        match u8::try_from(ix) {
+            Ok(ix) => self.emit_bytes(chunk, span, op, ix),
+            Err(_) => {
+                self.emit_byte(chunk, span, long_op);
+                self.emit_byte(chunk, span, ((ix >> 16) & 0xff) as u8);
+                self.emit_bytes(chunk,
+                                span,
+                                ((ix >> 8) & 0xff) as u8,
+                                (ix & 0xff) as u8);
+            }
+        }
+    }
+
+    // a forward jump with a placeholder offset. returns where the
+    // offset is, for `patch_jump`
+    fn emit_jump(&mut self,
+                 chunk: &mut crate::chunk::Chunk,
+                 span: &crate::scanner::Span,
+                 op: u8) -> usize {
+        self.emit_byte(chunk, span, op);
+        self.emit_bytes(chunk, span, 0xff, 0xff);
+        chunk.code.len() - 2
+    }
+
+    // point the jump whose offset is at `offset` at whatever we emit
+    // next. offsets are counted from the end of the jump instruction
+    fn patch_jump(&mut self,
+                  chunk: &mut crate::chunk::Chunk,
+                  span: &crate::scanner::Span,
+                  offset: usize) {
+        let jump = chunk.code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error(span,
+                       "Too much code to jump over.".to_string());
+            return;
+        }
+        chunk.code[offset] = ((jump >> 8) & 0xff) as u8;
+        chunk.code[offset + 1] = (jump & 0xff) as u8;
+    }
+
+    // jump back to `loop_start`
+    fn emit_loop(&mut self,
+                 chunk: &mut crate::chunk::Chunk,
+                 span: &crate::scanner::Span,
+                 loop_start: usize) {
+        self.emit_byte(chunk, span, opcode!(OPLOOP));
+
+        // +2 to also jump back over OPLOOP's own offset
+        let offset = chunk.code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error(span,
+                       "Loop body too large.".to_string());
+            return;
+        }
+        self.emit_bytes(chunk,
+                        span,
+                        ((offset >> 8) & 0xff) as u8,
+                        (offset & 0xff) as u8);
     }
 
 
     fn error(&mut self,
-             token: &crate::scanner::Token,
-             message: String,
-             source: &str) {
+             span: &crate::scanner::Span,
+             message: String) {
         if self.panic_mode {
             return;
         }
         self.had_error = true;
         self.panic_mode = true;
-        self.error_at(token, message, source);
+        self.error_at(span, message);
     }
 
-    fn error_at(&mut self,
-                token: &crate::scanner::Token,
-                message: String,
-                source: &str) {
-
-        print!("[line {}] Error", token.line);
-        if token.typ == crate::scanner::TokenType::EOF {
-            print!(" at end");
-        } else if token.typ == crate::scanner::TokenType::ERROR {
-            print!("");
-        } else {
-            print!(" at '{}'",
-                   &source[token.start..token.start+token.length]);
+    fn error_with_help(&mut self,
+                       span: &crate::scanner::Span,
+                       message: String,
+                       help: &str) {
+        if self.panic_mode {
+            return;
         }
-        println!(": {}", message);
+        self.error(span, message);
+        self.error = self.error.take().map(|e| e.with_help(help));
+    }
+
+    fn error_at(&mut self,
+                span: &crate::scanner::Span,
+                message: String) {
+        self.error = Some(crate::error::compile_error(
+            message,
+            &self.file,
+            &self.source,
+            span));
     }
 }
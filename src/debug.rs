@@ -1,7 +1,36 @@
 extern crate num_derive;
 use num::{FromPrimitive};
 
-pub fn disassemble_chunk(ref ch: &crate::chunk::Chunk, name: &str) {
+// something that wants to watch the VM run. `instruction` is called
+// with the instruction at `offset` in `chunk` about to execute
+pub trait Tracer {
+    fn instruction(&mut self,
+                   stack: &[crate::value::ValueType],
+                   chunk: &crate::chunk::Chunk,
+                   offset: usize);
+}
+
+// prints the stack, then the instruction, to stdout
+pub struct PrintTracer;
+
+impl Tracer for PrintTracer {
+    fn instruction(&mut self,
+                   stack: &[crate::value::ValueType],
+                   chunk: &crate::chunk::Chunk,
+                   offset: usize) {
+        print!("        ");
+        for s in stack.iter() {
+            print!("[ ");
+            crate::value::print_value(s);
+            print!(" ]");
+        }
+        println!();
+
+        disassemble_instruction(chunk, offset);
+    }
+}
+
+pub fn disassemble_chunk(ch: &crate::chunk::Chunk, name: &str) {
     println!("== {} ==", name);
     let mut offset: usize = 0;
 
@@ -11,13 +40,27 @@ pub fn disassemble_chunk(ref ch: &crate::chunk::Chunk, name: &str) {
 
 }
 
-pub fn disassemble_instruction(ref ch: &crate::chunk::Chunk, offset: usize) -> usize {
+// the function's chunk, then the chunk of every function defined in
+// it, and so on down
+pub fn disassemble_function(function: &crate::value::Function, name: &str) {
+    disassemble_chunk(&function.chunk, name);
+
+    for constant in function.chunk.constants.values.iter() {
+        if let crate::value::ConstantType::FUNCTION(f) = constant {
+            println!();
+            disassemble_function(f, &crate::value::function_name(f));
+        }
+    }
+}
+
+pub fn disassemble_instruction(ch: &crate::chunk::Chunk, offset: usize) -> usize {
     print!("{:04} ", offset);
 
-    if offset > 0 && ch.lines[offset] == ch.lines[offset-1] {
+    let line = ch.line_for(offset);
+    if offset > 0 && line == ch.line_for(offset - 1) {
         print!("   | ");
     } else {
-        print!("{:04} ", ch.lines[offset])
+        print!("{:04} ", line)
     }
 
     let instruction: Option<crate::chunk::Opcode> =
@@ -35,29 +78,124 @@ pub fn disassemble_instruction(ref ch: &crate::chunk::Chunk, offset: usize) -> u
         Some(crate::chunk::Opcode::OPFALSE) => simple_instruction("OP_FALSE",  offset),
         Some(crate::chunk::Opcode::OPNIL) => simple_instruction("OP_NIL",  offset),
         Some(crate::chunk::Opcode::OPNOT) => simple_instruction("OP_NOT",  offset),
-        Some(crate::chunk::Opcode::OPEQUAL) => simple_instruction("OP_EQUAL",  offset),
-        Some(crate::chunk::Opcode::OPLT) => simple_instruction("OP_LT",  offset),
-        Some(crate::chunk::Opcode::OPLTE) => simple_instruction("OP_LTE",  offset),
-        Some(crate::chunk::Opcode::OPGT) => simple_instruction("OP_GT",  offset),
-        Some(crate::chunk::Opcode::OPGTE) => simple_instruction("OP_GTE",  offset),
+        Some(crate::chunk::Opcode::OPEQUAL) => byte_instruction("OP_EQUAL", ch, offset),
+        Some(crate::chunk::Opcode::OPLT) => byte_instruction("OP_LT", ch, offset),
+        Some(crate::chunk::Opcode::OPLTE) => byte_instruction("OP_LTE", ch, offset),
+        Some(crate::chunk::Opcode::OPGT) => byte_instruction("OP_GT", ch, offset),
+        Some(crate::chunk::Opcode::OPGTE) => byte_instruction("OP_GTE", ch, offset),
         Some(crate::chunk::Opcode::OPLEN) => simple_instruction("OP_LEN",  offset),
         Some(crate::chunk::Opcode::OPPRINT) => simple_instruction("OP_PRINT",  offset),
         Some(crate::chunk::Opcode::OPPOP) => simple_instruction("OP_POP",  offset),
-        Some(crate::chunk::Opcode::OPDEF) => simple_instruction("OP_DEF",  offset),
-        Some(crate::chunk::Opcode::OPDEFSYM) => simple_instruction("OP_DEFSYM",  offset),
-        Some(crate::chunk::Opcode::OPSYM) => simple_instruction("OP_SYM",  offset),
-        Some(crate::chunk::Opcode::OPJMPIFFALSE) => simple_instruction("OP_JMPIFFALSE",  offset),
-        Some(crate::chunk::Opcode::OPJMP) => simple_instruction("OP_JMP",  offset),
+        Some(crate::chunk::Opcode::OPDEFINEGLOBAL) => byte_instruction("OP_DEFINE_GLOBAL", ch, offset),
+        Some(crate::chunk::Opcode::OPGETGLOBAL) => byte_instruction("OP_GET_GLOBAL", ch, offset),
+        Some(crate::chunk::Opcode::OPJUMPIFFALSE) => jump_instruction("OP_JUMP_IF_FALSE", 1, ch, offset),
+        Some(crate::chunk::Opcode::OPJUMP) => jump_instruction("OP_JUMP", 1, ch, offset),
+        Some(crate::chunk::Opcode::OPLOOP) => jump_instruction("OP_LOOP", -1, ch, offset),
+        Some(crate::chunk::Opcode::OPJUMPIFTRUE) => jump_instruction("OP_JUMP_IF_TRUE", 1, ch, offset),
+        Some(crate::chunk::Opcode::OPDUP) => simple_instruction("OP_DUP", offset),
+        Some(crate::chunk::Opcode::OPGETLOCAL) => byte_instruction("OP_GET_LOCAL", ch, offset),
+        Some(crate::chunk::Opcode::OPSETLOCAL) => byte_instruction("OP_SET_LOCAL", ch, offset),
+        Some(crate::chunk::Opcode::OPCALL) => byte_instruction("OP_CALL", ch, offset),
+        Some(crate::chunk::Opcode::OPTAILCALL) => byte_instruction("OP_TAIL_CALL", ch, offset),
+        Some(crate::chunk::Opcode::OPCLOSURE) => closure_instruction("OP_CLOSURE", ch, offset),
+        Some(crate::chunk::Opcode::OPGETUPVALUE) => byte_instruction("OP_GET_UPVALUE", ch, offset),
+        Some(crate::chunk::Opcode::OPCLOSEUPVALUE) => byte_instruction("OP_CLOSE_UPVALUE", ch, offset),
+        Some(crate::chunk::Opcode::OPCONSTANTLONG) => constant_long_instruction("OP_CONSTANT_LONG", ch, offset),
+        Some(crate::chunk::Opcode::OPDEFINEGLOBALLONG) => long_instruction("OP_DEFINE_GLOBAL_LONG", ch, offset),
+        Some(crate::chunk::Opcode::OPGETGLOBALLONG) => long_instruction("OP_GET_GLOBAL_LONG", ch, offset),
+        Some(crate::chunk::Opcode::OPCLOSURELONG) => closure_instruction("OP_CLOSURE_LONG", ch, offset),
+        Some(crate::chunk::Opcode::OPVECTOR) => short_instruction("OP_VECTOR", ch, offset),
+        Some(crate::chunk::Opcode::OPMAP) => short_instruction("OP_MAP", ch, offset),
+        Some(crate::chunk::Opcode::OPSET) => short_instruction("OP_SET", ch, offset),
 
         _ => simple_instruction("UNKNOWN OPCODE", offset),
     }
 }
 
 fn simple_instruction(name: &str, offset: usize) -> usize {
-    print!("{}\n", name);
+    println!("{}", name);
     offset + 1
 }
 
+fn byte_instruction(name: &str,
+                    chunk: &crate::chunk::Chunk,
+                    offset: usize) -> usize {
+    let slot = chunk.code[offset + 1];
+    println!("{:-16} {:4}", name, slot);
+    offset + 2
+}
+
+fn short_instruction(name: &str,
+                     chunk: &crate::chunk::Chunk,
+                     offset: usize) -> usize {
+    let operand = ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+    println!("{:-16} {:4}", name, operand);
+    offset + 3
+}
+
+// prints where the jump lands. `sign` is -1 for jumps backwards
+fn jump_instruction(name: &str,
+                    sign: i64,
+                    chunk: &crate::chunk::Chunk,
+                    offset: usize) -> usize {
+    let jump = ((chunk.code[offset + 1] as i64) << 8) | chunk.code[offset + 2] as i64;
+    println!("{:-16} {:4} -> {}", name, offset, offset as i64 + 3 + sign * jump);
+    offset + 3
+}
+
+// the constant, then a pair of (is_local, index) bytes per upvalue
+fn closure_instruction(name: &str,
+                       chunk: &crate::chunk::Chunk,
+                       offset: usize) -> usize {
+    let (mut offset, constant) = if chunk.code[offset] == crate::chunk::Opcode::OPCLOSURELONG as u8 {
+        (constant_long_instruction(name, chunk, offset), long_index(chunk, offset))
+    } else {
+        (constant_instruction(name, chunk, offset), chunk.code[offset + 1] as usize)
+    };
+
+    let upvalue_count = match &chunk.constants.values[constant] {
+        crate::value::ConstantType::FUNCTION(f) => f.upvalue_count,
+        _ => 0
+    };
+
+    for _ in 0..upvalue_count {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        println!("{:04}    |                     {} {}",
+                 offset,
+                 if is_local == 1 { "local" } else { "upvalue" },
+                 index);
+        offset += 2;
+    }
+    offset
+}
+
+fn long_index(chunk: &crate::chunk::Chunk, offset: usize) -> usize {
+    ((chunk.code[offset + 1] as usize) << 16)
+        | ((chunk.code[offset + 2] as usize) << 8)
+        | chunk.code[offset + 3] as usize
+}
+
+fn long_instruction(name: &str,
+                    chunk: &crate::chunk::Chunk,
+                    offset: usize) -> usize {
+    println!("{:-16} {:4}", name, long_index(chunk, offset));
+    offset + 4
+}
+
+fn constant_long_instruction(name: &str,
+                             chunk: &crate::chunk::Chunk,
+                             offset: usize) -> usize {
+    let constant = long_index(chunk, offset);
+    print!("{:-16} {:4} ", name, constant);
+
+    let vt = chunk.constants.values[constant].to_value();
+
+    crate::value::print_value(&vt);
+    println!();
+    offset + 4
+}
+
 fn constant_instruction(name: &str,
                         chunk: &crate::chunk::Chunk,
                         offset: usize) -> usize {
@@ -65,18 +203,9 @@ fn constant_instruction(name: &str,
     print!("{:-16} {:4} ", name, constant);
 
     // print_value needs a value, not a constant
-    let vt = match &chunk.constants.values[constant] {
-        crate::value::ConstantType::INT(n) =>
-            crate::value::ValueType::INT(*n),
-        crate::value::ConstantType::FLOAT(n) =>
-            crate::value::ValueType::FLOAT(*n),
-        crate::value::ConstantType::STRING(s) =>
-            crate::value::ValueType::STRING(s),
-        crate::value::ConstantType::SYMBOL(s) =>
-            crate::value::ValueType::SYMBOL(s)
-    };
+    let vt = chunk.constants.values[constant].to_value();
 
     crate::value::print_value(&vt);
-    println!("");
+    println!();
     offset + 2
 }
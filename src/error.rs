@@ -0,0 +1,183 @@
+use std::fmt;
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+}
+
+// everything that can go wrong between reading source and finishing
+// running it. `line` and `column` are 1-based; a column of 0 means we
+// don't know it
+#[derive(Debug)]
+pub struct SophieError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub file: String,
+    pub line: u32,
+    pub column: usize,
+
+    // how many chars from `column` to underline
+    pub length: usize,
+
+    // the text of `line`, so we can show it without going back to
+    // the file
+    pub source_line: Option<String>,
+
+    pub help: Option<String>,
+
+    // extra context, most specific first
+    pub notes: Vec<String>,
+
+    // for runtime errors, the calls that were in progress, innermost
+    // first
+    pub backtrace: Vec<Frame>,
+}
+
+// one call in a backtrace: which function, and where it was at
+#[derive(Debug)]
+pub struct Frame {
+    pub function: String,
+    pub file: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+// a stack overflow has a thousand frames, and nobody wants to read
+// them all
+const BACKTRACE_MAX: usize = 16;
+
+// an error about the code `span` covers
+pub fn compile_error(message: String,
+                     file: &str,
+                     source: &str,
+                     span: &crate::scanner::Span) -> Box<SophieError> {
+    error_at(ErrorKind::Compile, message, file, source, span)
+}
+
+// an error while running the code `span` covers
+pub fn runtime_error(message: String,
+                     file: &str,
+                     source: &str,
+                     span: &crate::scanner::Span) -> Box<SophieError> {
+    error_at(ErrorKind::Runtime, message, file, source, span)
+}
+
+fn error_at(kind: ErrorKind,
+            message: String,
+            file: &str,
+            source: &str,
+            span: &crate::scanner::Span) -> Box<SophieError> {
+    // only the first line of anything longer gets underlined
+    let line_end = source[span.start..].find('\n').map_or(source.len(), |i| span.start + i);
+    let end = std::cmp::min(span.end, line_end);
+    Box::new(SophieError {
+        kind,
+        message,
+        file: file.to_string(),
+        line: span.line,
+        column: span.col as usize,
+        length: source[span.start..end].chars().count(),
+        source_line: Some(line_at(source, span.start).to_string()),
+        help: None,
+        notes: Vec::new(),
+        backtrace: Vec::new(),
+    })
+}
+
+impl SophieError {
+    pub fn push_note(&mut self, note: String) {
+        self.notes.push(note);
+    }
+
+    pub fn with_help(mut self: Box<Self>, help: &str) -> Box<SophieError> {
+        self.help = Some(help.to_string());
+        self
+    }
+
+    // what the process should exit with, following sysexits.h the way
+    // clox does
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::Compile => 65,
+            ErrorKind::Runtime => 70,
+        }
+    }
+}
+
+// rendered the way rustc does it:
+//
+// error: Expected ')'.
+//  --> foo.sophie:2:6
+//   |
+// 2 |  (foo
+//   |      ^
+//   = help: ...
+//
+// with the backtrace, if there is one, last
+impl fmt::Display for SophieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            ErrorKind::Compile => "error",
+            ErrorKind::Runtime => "runtime error",
+        };
+        writeln!(f, "{}: {}", kind, self.message)?;
+
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        write!(f, "{}--> {}:{}", gutter, self.file, self.line)?;
+        if self.column > 0 {
+            write!(f, ":{}", self.column)?;
+        }
+
+        if let Some(text) = &self.source_line {
+            write!(f, "\n{} |", gutter)?;
+            write!(f, "\n{} | {}", self.line, text)?;
+
+            if self.column > 0 {
+                // tabs have to stay tabs or the carets won't line up
+                let padding: String = text.chars()
+                    .take(self.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                write!(f, "\n{} | {}{}",
+                       gutter,
+                       padding,
+                       "^".repeat(std::cmp::max(self.length, 1)))?;
+            }
+        }
+
+        if let Some(help) = &self.help {
+            write!(f, "\n{} = help: {}", gutter, help)?;
+        }
+
+        for note in self.notes.iter() {
+            write!(f, "\n{} = note: {}", gutter, note)?;
+        }
+
+        // a single frame is just where the error is, which we've
+        // already said
+        if self.backtrace.len() > 1 {
+            write!(f, "\n{} = backtrace:", gutter)?;
+            for (i, frame) in self.backtrace.iter().enumerate().take(BACKTRACE_MAX) {
+                write!(f, "\n{}     {}: {} at {}:{}:{}",
+                       gutter, i, frame.function, frame.file, frame.line, frame.col)?;
+            }
+            if self.backtrace.len() > BACKTRACE_MAX {
+                write!(f, "\n{}     ... and {} more", gutter, self.backtrace.len() - BACKTRACE_MAX)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SophieError {}
+
+// the whole line containing the byte at `offset`, without its newline
+fn line_at(source: &str, offset: usize) -> &str {
+    let start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let end = source[offset..].find('\n').map_or(source.len(), |i| offset + i);
+    source[start..end].trim_end_matches('\r')
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// every global the VM knows about, by slot. the compiler hands out a
+// slot the first time it sees a name, and the VM keeps the table
+// between runs, so code compiled later (the next line at the repl)
+// agrees with code compiled earlier about where each global lives
+#[derive(Debug)]
+pub struct Globals {
+    // None until something defs it
+    pub values: Vec<Option<crate::value::ValueType>>,
+    pub names: Vec<Rc<str>>,
+    slots: HashMap<crate::interner::Id, usize>,
+}
+
+pub fn init_globals() -> Globals {
+    Globals {
+        values: Vec::new(),
+        names: Vec::new(),
+        slots: HashMap::new(),
+    }
+}
+
+impl Globals {
+    // `name` has to be interned
+    pub fn slot(&mut self, name: &Rc<str>) -> usize {
+        let id = crate::interner::id(name);
+        if let Some(slot) = self.slots.get(&id) {
+            return *slot;
+        }
+
+        let slot = self.values.len();
+        self.values.push(None);
+        self.names.push(Rc::clone(name));
+        self.slots.insert(id, slot);
+        slot
+    }
+}
@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// hands out one shared copy of each distinct string, so that anything
+// interned can be compared by pointer rather than by content. there's
+// one per VM, and the compiler interns symbols, keywords and string
+// literals through it, so the same name is the same pointer across
+// every chunk the VM runs
+#[derive(Debug)]
+pub struct Interner {
+    strings: HashSet<Rc<str>>,
+}
+
+pub fn init_interner() -> Interner {
+    Interner {
+        strings: HashSet::new(),
+    }
+}
+
+impl Interner {
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        match self.strings.get(s) {
+            Some(interned) => Rc::clone(interned),
+            None => {
+                let interned: Rc<str> = s.into();
+                self.strings.insert(Rc::clone(&interned));
+                interned
+            }
+        }
+    }
+}
+
+// what an interned string is compared and hashed by. the interner
+// keeps everything it hands out alive, so an address is never reused
+// for a different string
+pub type Id = usize;
+
+pub fn id(s: &Rc<str>) -> Id {
+    Rc::as_ptr(s) as *const u8 as Id
+}
@@ -1,51 +1,158 @@
+// the code follows clox's naming (OPCONSTANT, TokenType::EOF)
+#![allow(clippy::upper_case_acronyms)]
+
 #[macro_use]
 mod alloc;
 
 #[macro_use]
 mod value;
+mod interner;
+mod globals;
+mod collections;
 mod chunk;
 mod debug;
 mod vm;
 mod compiler;
 mod scanner;
+mod reader;
+mod validate;
+mod native;
+mod error;
 
 use std::env;
 use std::fs;
-use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+use std::process;
 
 extern crate num;
 
 #[macro_use]
 extern crate num_derive;
 
+// what to do with each piece of source we're given
+#[derive(Clone, Copy)]
+enum Mode {
+    RUN,
+    DISASSEMBLE,
+    DUMPAST,
+}
+
 fn main() {
 
     let args: Vec<String> = env::args().collect();
 
-    let filename = &args[1];
+    let mut vm = crate::vm::init_vm();
+    let mut mode = Mode::RUN;
+    let mut path: Option<&str> = None;
 
-    // let vm = crate::vm::init_vm();
-    let mut chunk = crate::chunk::init_chunk();
+    for arg in args.iter().skip(1) {
+        match arg.as_str() {
+            "--trace" => vm.tracer = Some(Box::new(crate::debug::PrintTracer)),
+            "--disassemble" => mode = Mode::DISASSEMBLE,
+            "--dump-ast" => mode = Mode::DUMPAST,
+            _ if arg.starts_with("--") || path.is_some() => usage(),
+            _ => path = Some(arg)
+        }
+    }
 
-    let mut vm = crate::vm::VM {
-       ip: 0,
-        stack: Vec::new(),
-        symbols: HashMap::new()
-    };
+    match path {
+        None => repl(&mut vm, mode),
+        Some(p) => run_file(p, &mut vm, mode),
+    }
+}
 
-    let _res = run_file(&filename, &mut vm, &mut chunk);
-    // res
+fn usage() -> ! {
+    eprintln!("Usage: sophie [--trace | --disassemble | --dump-ast] [path]");
+    process::exit(64);
 }
 
-fn run_file<'a>(filename: &'a str,
-                vm: &'a mut crate::vm::VM<'a>,
-                chunk: &'a mut crate::chunk::Chunk)  {
+// run the source, or just show what running it would involve. only
+// running it produces a value
+fn process(vm: &mut crate::vm::VM,
+           mode: Mode,
+           source: &str,
+           file: &str) -> Result<Option<crate::value::ValueType>, Box<crate::error::SophieError>> {
+    match mode {
+        Mode::RUN => vm.interpret(source, file).map(Some),
+        Mode::DISASSEMBLE => {
+            let file_id = vm.file_id(file);
+            let function = crate::compiler::compile_script(source,
+                                                           file,
+                                                           file_id,
+                                                           &mut vm.interner,
+                                                           &mut vm.globals)?;
+            crate::debug::disassemble_function(&function, "<script>");
+            Ok(None)
+        },
+        Mode::DUMPAST => crate::reader::dump_ast(source, file, vm.file_id(file)).map(|_| None),
+    }
+}
+
+// read a form (which may take several lines), run it, print the
+// result, repeat. errors are reported and then we carry on; whatever
+// was `def`d before the error is still there
+fn repl(vm: &mut crate::vm::VM, mode: Mode) {
+    let stdin = io::stdin();
+    let mut source = String::new();
+
+    loop {
+        if source.is_empty() {
+            print!("> ");
+        } else {
+            print!(". ");
+        }
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                break;
+            },
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Failed to read input: {}", e);
+                break;
+            }
+        }
+        source.push_str(&line);
+
+        // keep reading until the parens balance
+        if !crate::scanner::is_complete(&source) {
+            continue;
+        }
+
+        if !source.trim().is_empty() {
+            match process(vm, mode, &source, "<repl>") {
+                Ok(Some(v)) => {
+                    crate::value::print_value(&v);
+                    println!();
+                },
+                Ok(None) => (),
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+        source.clear();
+    }
+}
+
+fn run_file(filename: &str,
+            vm: &mut crate::vm::VM,
+            mode: Mode) {
 
     let contents = fs::read_to_string(filename)
         .expect("Failed to read source");
 
-    vm.interpret(&contents, chunk);
-
-    //if (result == INTERPRET_COMPILE_ERROR) exit(65);
-    //if (result == INTERPRET_RUNTIME_ERROR) exit(70);
+    match process(vm, mode, &contents, filename) {
+        Ok(Some(v)) => {
+            crate::value::print_value(&v);
+            println!();
+        },
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(e.exit_code());
+        }
+    }
 }
@@ -0,0 +1,137 @@
+use std::rc::Rc;
+
+// functions implemented in rust, bound as globals when a VM starts
+
+pub fn define_natives(vm: &mut crate::vm::VM) {
+    vm.define_native("gc-stats", 0, gc_stats);
+    vm.define_native("get", 2, get);
+    vm.define_native("assoc", 3, assoc);
+    vm.define_native("conj", 2, conj);
+    vm.define_native("count", 1, count);
+    vm.define_native("nth", 2, nth);
+}
+
+// (gc-stats) => "bytes-allocated 1234 next-gc 1048576 ..."
+fn gc_stats(vm: &mut crate::vm::VM,
+            _args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    let stats = format!("bytes-allocated {} next-gc {} collections {} bytes-freed {} objects {}",
+                        vm.heap.bytes_allocated,
+                        vm.heap.next_gc,
+                        vm.heap.collections,
+                        vm.heap.bytes_freed,
+                        vm.heap.object_count());
+    let s: Rc<str> = vm.heap.alloc_string(stats);
+    Ok(crate::value::ValueType::STRING(s))
+}
+
+// a vector index, which has to be a non-negative int
+fn index(value: &crate::value::ValueType) -> Result<usize, String> {
+    match value {
+        crate::value::ValueType::INT(n) if *n >= 0 => Ok(*n as usize),
+        crate::value::ValueType::INT(n) => Err(format!("Index {} is negative.", n)),
+        _ => Err(format!("Index must be an int, not a {}.",
+                         crate::value::type_name(value)))
+    }
+}
+
+// (get {"a" 1} "a") => 1
+// (get [1 2 3] 0) => 1
+// (get #{1 2} 2) => 2
+// nil if it isn't there
+fn get(_vm: &mut crate::vm::VM,
+       args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    let found = match (&args[0], &args[1]) {
+        (crate::value::ValueType::MAP(m), key) => m.get(key).cloned(),
+        (crate::value::ValueType::VECTOR(v), crate::value::ValueType::INT(i)) if *i >= 0 =>
+            v.get(*i as usize).cloned(),
+        (crate::value::ValueType::SET(s), key) if s.contains(key) => Some(key.clone()),
+        _ => None
+    };
+    Ok(found.unwrap_or(crate::value::ValueType::NIL))
+}
+
+// (assoc {"a" 1} "b" 2) => {"a" 1, "b" 2}
+// (assoc [1 2 3] 0 4) => [4 2 3]
+fn assoc(vm: &mut crate::vm::VM,
+         args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    match &args[0] {
+        crate::value::ValueType::MAP(m) => {
+            let m = m.assoc(args[1].clone(), args[2].clone());
+            Ok(crate::value::ValueType::MAP(vm.heap.alloc_map(m)))
+        },
+        crate::value::ValueType::NIL => {
+            let m = crate::collections::init_map(vec![(args[1].clone(), args[2].clone())]);
+            Ok(crate::value::ValueType::MAP(vm.heap.alloc_map(m)))
+        },
+        crate::value::ValueType::VECTOR(v) => {
+            let i = index(&args[1])?;
+            match v.assoc(i, args[2].clone()) {
+                Some(v) => Ok(crate::value::ValueType::VECTOR(vm.heap.alloc_vector(v))),
+                None => Err(format!("Index {} out of bounds for vector of length {}.",
+                                    i, v.len()))
+            }
+        },
+        other => Err(format!("Can't assoc on a {}.", crate::value::type_name(other)))
+    }
+}
+
+// (conj [1 2] 3) => [1 2 3]
+// (conj #{1 2} 3) => #{1 2 3}
+// (conj {"a" 1} ["b" 2]) => {"a" 1, "b" 2}
+fn conj(vm: &mut crate::vm::VM,
+        args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    match &args[0] {
+        crate::value::ValueType::VECTOR(v) => {
+            let v = v.conj(args[1].clone());
+            Ok(crate::value::ValueType::VECTOR(vm.heap.alloc_vector(v)))
+        },
+        crate::value::ValueType::NIL => {
+            let v = crate::collections::init_vector(vec![args[1].clone()]);
+            Ok(crate::value::ValueType::VECTOR(vm.heap.alloc_vector(v)))
+        },
+        crate::value::ValueType::SET(s) => {
+            let s = s.conj(args[1].clone());
+            Ok(crate::value::ValueType::SET(vm.heap.alloc_set(s)))
+        },
+        crate::value::ValueType::MAP(m) => match &args[1] {
+            crate::value::ValueType::VECTOR(entry) if entry.len() == 2 => {
+                let m = m.assoc(entry.get(0).unwrap().clone(),
+                                entry.get(1).unwrap().clone());
+                Ok(crate::value::ValueType::MAP(vm.heap.alloc_map(m)))
+            },
+            _ => Err("Can only conj [key value] vectors onto a map.".to_string())
+        },
+        other => Err(format!("Can't conj onto a {}.", crate::value::type_name(other)))
+    }
+}
+
+// (count [1 2 3]) => 3
+fn count(_vm: &mut crate::vm::VM,
+         args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    let n = match &args[0] {
+        crate::value::ValueType::VECTOR(v) => v.len(),
+        crate::value::ValueType::MAP(m) => m.len(),
+        crate::value::ValueType::SET(s) => s.len(),
+        crate::value::ValueType::STRING(s) => s.chars().count(),
+        crate::value::ValueType::NIL => 0,
+        other => return Err(format!("Can't count a {}.", crate::value::type_name(other)))
+    };
+    Ok(crate::value::ValueType::INT(n as i64))
+}
+
+// (nth [1 2 3] 1) => 2
+// unlike `get`, it's an error to go off the end
+fn nth(_vm: &mut crate::vm::VM,
+       args: &[crate::value::ValueType]) -> Result<crate::value::ValueType, String> {
+    match &args[0] {
+        crate::value::ValueType::VECTOR(v) => {
+            let i = index(&args[1])?;
+            match v.get(i) {
+                Some(item) => Ok(item.clone()),
+                None => Err(format!("Index {} out of bounds for vector of length {}.",
+                                    i, v.len()))
+            }
+        },
+        other => Err(format!("Can't nth a {}.", crate::value::type_name(other)))
+    }
+}
@@ -0,0 +1,272 @@
+use std::str::FromStr;
+
+// source -> forms. the reader knows what's a list and what's a
+// vector, but nothing about what any of it means; that's the
+// compiler's job
+
+#[derive(Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: crate::scanner::Span,
+}
+
+#[derive(Debug)]
+pub enum ExprKind {
+    LIST(Vec<Expr>),
+    VECTOR(Vec<Expr>),
+
+    // keys and values, alternating
+    MAP(Vec<Expr>),
+    SET(Vec<Expr>),
+
+    // names, including the ones with special meaning (`if`, `+`)
+    SYMBOL(String),
+
+    // without the leading ':'
+    KEYWORD(String),
+    LITERAL(Literal),
+
+    // 'x
+    QUOTE(Box<Expr>),
+}
+
+#[derive(Debug)]
+pub enum Literal {
+    INT(i64),
+    FLOAT(f64),
+    STRING(String),
+    BOOL(bool),
+    NIL,
+}
+
+pub struct Reader<'a> {
+    current: crate::scanner::Token,
+    error: Option<Box<crate::error::SophieError>>,
+    scanner: crate::scanner::Scanner<'a>,
+    source: &'a str,
+    file: &'a str,
+}
+
+// every form at the top level of `source`, in order
+pub fn read(source: &str,
+            file: &str,
+            file_id: crate::scanner::FileId) -> Result<Vec<Expr>, Box<crate::error::SophieError>> {
+    let mut reader = Reader {
+        current: crate::scanner::Token {
+            typ: crate::scanner::TokenType::NOOP,
+            span: crate::scanner::Span {
+                file_id,
+                start: 0,
+                end: 0,
+                line: 0,
+                col: 0,
+            },
+            error: None},
+        error: None,
+        scanner: crate::scanner::init_scanner(file_id),
+        source,
+        file,
+    };
+
+    let mut forms = Vec::new();
+    advance(&mut reader);
+    while reader.current.typ != crate::scanner::TokenType::EOF {
+        match form(&mut reader) {
+            Some(f) => forms.push(f),
+            None => break
+        }
+        advance(&mut reader);
+    }
+
+    match reader.error {
+        Some(err) => Err(err),
+        None => Ok(forms)
+    }
+}
+
+// reads the form starting at the current token, leaving the last
+// token of the form current. None if there was an error
+fn form(reader: &mut Reader) -> Option<Expr> {
+    let span = reader.current.span;
+    let text = &reader.source[span.start..span.end];
+
+    let kind = match reader.current.typ {
+        crate::scanner::TokenType::LEFTPAREN =>
+            return sequence(reader, crate::scanner::TokenType::RIGHTPAREN, ExprKind::LIST),
+        crate::scanner::TokenType::LEFTBRACKET =>
+            return sequence(reader, crate::scanner::TokenType::RIGHTBRACKET, ExprKind::VECTOR),
+        crate::scanner::TokenType::HASHBRACE =>
+            return sequence(reader, crate::scanner::TokenType::RIGHTBRACE, ExprKind::SET),
+        crate::scanner::TokenType::LEFTBRACE => {
+            let map = sequence(reader, crate::scanner::TokenType::RIGHTBRACE, ExprKind::MAP)?;
+            if let ExprKind::MAP(items) = &map.kind {
+                if items.len() % 2 != 0 {
+                    error(reader,
+                          map.span,
+                          "Map literal must have an even number of forms.".to_string());
+                    return None;
+                }
+            }
+            return Some(map);
+        },
+
+        crate::scanner::TokenType::RIGHTPAREN |
+        crate::scanner::TokenType::RIGHTBRACKET |
+        crate::scanner::TokenType::RIGHTBRACE => {
+            error(reader, span, format!("Unexpected '{}'.", text));
+            return None;
+        },
+
+        crate::scanner::TokenType::QUOTE => {
+            advance(reader);
+            if reader.current.typ == crate::scanner::TokenType::EOF {
+                error(reader, span, "Expected a form to quote.".to_string());
+                return None;
+            }
+            let quoted = form(reader)?;
+            return Some(Expr {
+                span: crate::scanner::Span {
+                    end: quoted.span.end,
+                    ..span
+                },
+                kind: ExprKind::QUOTE(Box::new(quoted)),
+            });
+        },
+
+        crate::scanner::TokenType::INT => match i64::from_str(text) {
+            Ok(n) => ExprKind::LITERAL(Literal::INT(n)),
+            Err(_) => {
+                error(reader, span, "Integer literal is too large.".to_string());
+                return None;
+            }
+        },
+        crate::scanner::TokenType::FLOAT =>
+            ExprKind::LITERAL(Literal::FLOAT(f64::from_str(text).unwrap())),
+        crate::scanner::TokenType::STRING => {
+            if text.len() < 2 || !text.ends_with('"') {
+                error(reader, span, "Unterminated string.".to_string());
+                return None;
+            }
+            // drop the quotes
+            ExprKind::LITERAL(Literal::STRING(text[1..text.len()-1].to_string()))
+        },
+        crate::scanner::TokenType::TRUE => ExprKind::LITERAL(Literal::BOOL(true)),
+        crate::scanner::TokenType::FALSE => ExprKind::LITERAL(Literal::BOOL(false)),
+        crate::scanner::TokenType::NIL => ExprKind::LITERAL(Literal::NIL),
+        // drop the ':'
+        crate::scanner::TokenType::KEYWORD => ExprKind::KEYWORD(text[1..].to_string()),
+
+        // identifiers, operators, and the words the scanner
+        // recognises (`if`, `let`) are all just symbols here
+        _ => ExprKind::SYMBOL(text.to_string()),
+    };
+
+    Some(Expr {
+        kind,
+        span,
+    })
+}
+
+// forms up to the `close` token
+fn sequence(reader: &mut Reader,
+            close: crate::scanner::TokenType,
+            kind: fn(Vec<Expr>) -> ExprKind) -> Option<Expr> {
+    let open = reader.current.span;
+    let mut items = Vec::new();
+
+    loop {
+        advance(reader);
+        if reader.current.typ == close {
+            break;
+        }
+
+        if reader.current.typ == crate::scanner::TokenType::EOF {
+            let closing = match close {
+                crate::scanner::TokenType::RIGHTBRACKET => ']',
+                crate::scanner::TokenType::RIGHTBRACE => '}',
+                _ => ')',
+            };
+            let here = reader.current.span;
+            error(reader, here, format!("Expected '{}'.", closing));
+            return None;
+        }
+
+        items.push(form(reader)?);
+    }
+
+    Some(Expr {
+        kind: kind(items),
+        span: crate::scanner::Span {
+            end: reader.current.span.end,
+            ..open
+        },
+    })
+}
+
+fn advance(reader: &mut Reader) {
+    reader.current = crate::scanner::scan_token(&mut reader.scanner, reader.source);
+
+    if let Some(message) = reader.current.error.clone() {
+        let span = reader.current.span;
+        error(reader, span, message);
+
+        // stop reading
+        reader.current.typ = crate::scanner::TokenType::EOF;
+    }
+}
+
+// only the first error is kept
+fn error(reader: &mut Reader, span: crate::scanner::Span, message: String) {
+    if reader.error.is_some() {
+        return;
+    }
+    reader.error = Some(crate::error::compile_error(
+        message,
+        reader.file,
+        reader.source,
+        &span));
+}
+
+// print the forms `compile` would work from, one per line, with the
+// contents of collections indented underneath
+pub fn dump_ast(source: &str,
+                file: &str,
+                file_id: crate::scanner::FileId) -> Result<(), Box<crate::error::SophieError>> {
+    for expr in read(source, file, file_id)?.iter() {
+        dump_expr(expr, 0);
+    }
+    Ok(())
+}
+
+fn dump_expr(expr: &Expr, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let at = format!("line {}, col {}", expr.span.line, expr.span.col);
+
+    let children = match &expr.kind {
+        ExprKind::LIST(items) => { println!("{}LIST ({})", indent, at); items },
+        ExprKind::VECTOR(items) => { println!("{}VECTOR ({})", indent, at); items },
+        ExprKind::MAP(items) => { println!("{}MAP ({})", indent, at); items },
+        ExprKind::SET(items) => { println!("{}SET ({})", indent, at); items },
+        ExprKind::QUOTE(quoted) => {
+            println!("{}QUOTE ({})", indent, at);
+            dump_expr(quoted, depth + 1);
+            return;
+        },
+        ExprKind::SYMBOL(name) => {
+            println!("{}SYMBOL {} ({})", indent, name, at);
+            return;
+        },
+        ExprKind::KEYWORD(name) => {
+            println!("{}KEYWORD :{} ({})", indent, name, at);
+            return;
+        },
+        ExprKind::LITERAL(literal) => {
+            println!("{}LITERAL {:?} ({})", indent, literal, at);
+            return;
+        },
+    };
+
+    for child in children.iter() {
+        dump_expr(child, depth + 1);
+    }
+}
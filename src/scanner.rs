@@ -16,12 +16,14 @@ pub enum TokenType {
     LEFTANGLEBRACKET, RIGHTANGLEBRACKET,
     COMMA, DOT, MINUS, PLUS,
     SEMICOLON, SLASH, STAR,
+    QUOTE,
 
     // One or two character tokens.
     BANG, BANGEQUAL,
     EQUAL, EQUALEQUAL, // EQEQ not actually used
     GREATER, GREATEREQUAL,
     LESS, LESSEQUAL,
+    HASHBRACE,
 
     // Literals.
     IDENTIFIER, STRING,
@@ -45,7 +47,7 @@ fn init_token_trie() -> Trie<&'static str, TokenType> {
     let mut trie = Trie::new();
 
     trie.insert("and", TokenType::AND);
-    trie.insert("or", TokenType::AND);
+    trie.insert("or", TokenType::OR);
     trie.insert("false", TokenType::FALSE);
     trie.insert("if", TokenType::IF);
     trie.insert("let", TokenType::LET);
@@ -55,14 +57,26 @@ fn init_token_trie() -> Trie<&'static str, TokenType> {
     trie.insert("not", TokenType::NOT);
     trie.insert("len", TokenType::LEN);
     trie.insert("def", TokenType::DEF);
+    trie.insert("fn", TokenType::FUN);
 
     trie
 }
 
+// which file some source came from. the VM hands these out
+pub type FileId = u32;
+
 #[derive(Debug)]
 pub struct Scanner<'a> {
-    pub line: u16,
+    pub file_id: FileId,
+    pub line: u32,
+
+    // the column `current` is at, counting chars from 1
+    pub col: u32,
     pub start: usize,
+
+    // where `start` is, 1-based, counting chars rather than bytes
+    pub start_line: u32,
+    pub start_col: u32,
     pub current: usize,
     pub tokens: Trie<&'a str, TokenType>
 }
@@ -70,17 +84,33 @@ pub struct Scanner<'a> {
 #[derive(Debug)]
 pub struct Token {
     pub typ: TokenType,
-    pub line: u16,
-    pub start: usize,
-    pub length: usize,
+    pub span: Span,
     pub error: Option<String>,
 }
 
-pub fn init_scanner<'a>() -> Scanner<'a> {
+// a stretch of source: the bytes from `start` up to `end`, beginning
+// on `line` at column `col` (in chars, from 1). a `col` of 0 means we
+// don't know
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub struct Span {
+    pub file_id: FileId,
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub col: u32,
+}
+
+pub fn init_scanner<'a>(file_id: FileId) -> Scanner<'a> {
 
     Scanner {
+        file_id,
         line: 1,
+        col: 1,
         start: 0,
+        start_line: 1,
+        start_col: 1,
         current: 0,
         tokens: init_token_trie(),
     }
@@ -91,8 +121,13 @@ pub fn scan_token(scanner: &mut Scanner, source: &str) -> Token {
     scanner.start = scanner.current;
     skip_whitespace(scanner, source);
 
+    // strings can run over several lines, so note where the token
+    // starts before scanning it
+    scanner.start_line = scanner.line;
+    scanner.start_col = scanner.col;
+
     if is_at_end(scanner, source) {
-        return make_token(TokenType::EOF, &scanner);
+        return make_token(TokenType::EOF, scanner);
     }
 
     let c = advance(scanner, source);
@@ -111,6 +146,14 @@ pub fn scan_token(scanner: &mut Scanner, source: &str) -> Token {
         '+' => make_token(TokenType::PLUS, scanner),
         '*' => make_token(TokenType::STAR, scanner),
         '/' => make_token(TokenType::SLASH, scanner),
+        '\'' => make_token(TokenType::QUOTE, scanner),
+        '#' => {
+            if char_match('{', scanner, source) {
+                make_token(TokenType::HASHBRACE, scanner)
+            } else {
+                error_token("Expected '{' after '#'.".to_string(), scanner)
+            }
+        },
         '!' => {
             if char_match('=', scanner, source) {
                 make_token(TokenType::BANGEQUAL, scanner)
@@ -148,9 +191,9 @@ pub fn scan_token(scanner: &mut Scanner, source: &str) -> Token {
 
 }
 
+// the ':' has already been consumed
 fn keyword(scanner: &mut Scanner, source: &str) -> Token {
-    advance(scanner, source); // move past ':'
-    scanner.start += 1;
+    let name_start = scanner.current;
     loop {
         if is_at_end(scanner, source) {
             break;
@@ -166,6 +209,10 @@ fn keyword(scanner: &mut Scanner, source: &str) -> Token {
         };
     }
 
+    if scanner.current == name_start {
+        return error_token("Expected a keyword name after ':'.".to_string(), scanner);
+    }
+
     make_token(TokenType::KEYWORD, scanner)
 }
 
@@ -215,14 +262,14 @@ fn number(scanner: &mut Scanner, source: &str) -> Token {
         advance(scanner, source);
     }
 
-    if (has_point) {
+    if has_point {
         make_token(TokenType::FLOAT, scanner)
     } else {
         make_token(TokenType::INT, scanner)
     }
 }
 
-fn string<'a>(scanner: &mut Scanner, source: &'a str) -> Token {
+fn string(scanner: &mut Scanner, source: &str) -> Token {
     loop {
         if is_at_end(scanner, source) {
             break;
@@ -230,19 +277,18 @@ fn string<'a>(scanner: &mut Scanner, source: &'a str) -> Token {
 
         let c = peek(scanner, source);
 
+        advance(scanner, source);
         match c {
-            '"' => { advance(scanner, source); break; },
-            '\n' => scanner.line += 1,
+            '"' => break,
+            '\n' => new_line(scanner),
             _ => ()
         }
-
-        advance(scanner, source);
     }
 
     make_token(TokenType::STRING, scanner)
 }
 
-fn skip_whitespace<'a>(scanner: &mut Scanner, source: &'a str) {
+fn skip_whitespace(scanner: &mut Scanner, source: &str) {
     loop {
         if is_at_end(scanner, source) {
             break;
@@ -256,8 +302,8 @@ fn skip_whitespace<'a>(scanner: &mut Scanner, source: &'a str) {
                 scanner.start = scanner.current;
             },
             '\n' => {
-                scanner.line += 1;
                 advance(scanner, source);
+                new_line(scanner);
                 scanner.start = scanner.current;
             },
             _ => break
@@ -269,25 +315,35 @@ fn peek(scanner: &Scanner, source: &str) -> char {
     source[scanner.current..].chars().next().unwrap()
 }
 
+// the char after the next one, or '\0' if there isn't one
 fn peek_next(scanner: &Scanner, source: &str) -> char {
-    source[scanner.current+1..].chars().next().unwrap()
+    source[scanner.current..].chars().nth(1).unwrap_or('\0')
 }
 
-fn char_match<'a>(expected: char, scanner: &mut Scanner, source: &'a str) -> bool {
+fn char_match(expected: char, scanner: &mut Scanner, source: &str) -> bool {
     if is_at_end(scanner, source) {
         return false;
     }
 
-    if &source[scanner.current..scanner.current+1] != expected.to_string() {
+    if peek(scanner, source) != expected {
         return false;
     }
-    scanner.current += 1;
+    advance(scanner, source);
     true
 }
 
-pub fn advance<'a>(scanner: &mut Scanner, source: &'a str) -> char {
-    scanner.current += 1;
-    source[scanner.current - 1..].chars().next().unwrap()
+// steps over a whole char, however many bytes it takes
+pub fn advance(scanner: &mut Scanner, source: &str) -> char {
+    let c = peek(scanner, source);
+    scanner.current += c.len_utf8();
+    scanner.col += 1;
+    c
+}
+
+// call having just advanced past a '\n'
+fn new_line(scanner: &mut Scanner) {
+    scanner.line += 1;
+    scanner.col = 1;
 }
 
 fn is_at_end(scanner: &Scanner, source: &str) -> bool {
@@ -297,20 +353,99 @@ fn is_at_end(scanner: &Scanner, source: &str) -> bool {
 fn make_token(typ: TokenType, scanner: &Scanner) -> Token {
 
     Token {
-        typ: typ,
-        line: scanner.line,
-        start: scanner.start,
-        length: scanner.current - scanner.start,
+        typ,
+        span: current_span(scanner),
         error: None,
     }
 }
 
+// from the start of the token we're scanning up to where we are now
+fn current_span(scanner: &Scanner) -> Span {
+    Span {
+        file_id: scanner.file_id,
+        start: scanner.start,
+        end: scanner.current,
+        line: scanner.start_line,
+        col: scanner.start_col,
+    }
+}
+
+// true if `source` doesn't leave any parens, brackets, braces or
+// strings open, and so is worth handing to the compiler
+pub fn is_complete(source: &str) -> bool {
+    // where it came from doesn't matter here
+    let mut scanner = init_scanner(0);
+    let mut depth: i32 = 0;
+
+    loop {
+        let token = scan_token(&mut scanner, source);
+        match token.typ {
+            TokenType::EOF => return depth <= 0,
+            TokenType::LEFTPAREN | TokenType::LEFTBRACKET |
+            TokenType::LEFTBRACE | TokenType::HASHBRACE =>
+                depth += 1,
+            TokenType::RIGHTPAREN | TokenType::RIGHTBRACKET | TokenType::RIGHTBRACE =>
+                depth -= 1,
+            TokenType::STRING => {
+                let text = &source[token.span.start..token.span.end];
+                if text.len() < 2 || !text.ends_with('"') {
+                    return false;
+                }
+            },
+            _ => ()
+        }
+    }
+}
+
 fn error_token(message: String, scanner: &Scanner) -> Token {
     Token {
         typ: TokenType::ERROR,
-        line: scanner.line,
-        start: scanner.start,
-        length: scanner.current - scanner.start,
+        span: current_span(scanner),
         error: Some(message)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    // (text, line, col) for each token up to EOF
+    fn scan(source: &str) -> Vec<(&str, u32, u32)> {
+        let mut scanner = super::init_scanner(0);
+        let mut tokens = Vec::new();
+        loop {
+            let token = super::scan_token(&mut scanner, source);
+            if token.typ == super::TokenType::EOF {
+                return tokens;
+            }
+            let span = token.span;
+            tokens.push((&source[span.start..span.end], span.line, span.col));
+        }
+    }
+
+    #[test]
+    fn columns_count_chars_not_bytes() {
+        assert_eq!(scan("\"日本\" x"), vec![("\"日本\"", 1, 1), ("x", 1, 6)]);
+        assert_eq!(scan("(print \"é\")\n(+ \"ü\" :k)"),
+                   vec![("(", 1, 1), ("print", 1, 2), ("\"é\"", 1, 8), (")", 1, 11),
+                        ("(", 2, 1), ("+", 2, 2), ("\"ü\"", 2, 4), (":k", 2, 8), (")", 2, 10)]);
+
+        // the span's bytes still cover the whole token
+        let mut scanner = super::init_scanner(0);
+        super::scan_token(&mut scanner, "\"日本\" x");
+        let x = super::scan_token(&mut scanner, "\"日本\" x");
+        assert_eq!((x.span.start, x.span.end), (9, 10));
+    }
+
+    #[test]
+    fn strings_spanning_lines() {
+        // a string starts where its quote is, and whatever follows
+        // it is counted from its last line
+        assert_eq!(scan("(a \"one\ntwo\" b)\nc"),
+                   vec![("(", 1, 1), ("a", 1, 2), ("\"one\ntwo\"", 1, 4), ("b", 2, 6),
+                        (")", 2, 7), ("c", 3, 1)]);
+    }
+
+    #[test]
+    fn a_tab_is_one_column() {
+        assert_eq!(scan("\t(x\t y)"), vec![("(", 1, 2), ("x", 1, 3), ("y", 1, 6), (")", 1, 7)]);
+    }
+}
@@ -0,0 +1,333 @@
+// checks the forms from the reader before any code is generated: that
+// every special form and builtin op gets the right number of
+// arguments, and that the special forms are shaped the way they need
+// to be (`def` gets a symbol, `let` a vector of bindings, ...)
+
+// (name, fewest arguments, most arguments if there's a limit)
+const ARITIES: &[(&str, usize, Option<usize>)] = &[
+    ("if", 2, Some(3)),
+    ("def", 2, Some(2)),
+    ("let", 1, None),
+    ("loop", 1, None),
+    ("recur", 0, None),
+    ("fn", 1, None),
+    ("do", 0, None),
+    ("when", 1, None),
+    ("unless", 1, None),
+    ("cond", 0, None),
+    ("and", 0, None),
+    ("or", 0, None),
+
+    ("+", 0, None),
+    ("*", 0, None),
+    ("-", 1, None),
+    ("/", 1, None),
+    ("=", 1, None),
+    ("<", 1, None),
+    (">", 1, None),
+    ("<=", 1, None),
+    (">=", 1, None),
+    ("not", 1, Some(1)),
+    ("len", 1, Some(1)),
+    ("print", 1, Some(1)),
+];
+
+const MAX_PARAMS: usize = u8::MAX as usize;
+
+pub struct Validator<'a> {
+    file: &'a str,
+    source: &'a str,
+}
+
+// the first problem with any of `forms`, if there is one
+pub fn validate(forms: &[crate::reader::Expr],
+                file: &str,
+                source: &str) -> Result<(), Box<crate::error::SophieError>> {
+    let validator = Validator {
+        file,
+        source,
+    };
+
+    for form in forms.iter() {
+        validator.expr(form)?;
+    }
+    Ok(())
+}
+
+impl Validator<'_> {
+    fn expr(&self, expr: &crate::reader::Expr) -> Result<(), Box<crate::error::SophieError>> {
+        match &expr.kind {
+            crate::reader::ExprKind::LIST(items) => self.list(expr, items),
+            crate::reader::ExprKind::VECTOR(items) |
+            crate::reader::ExprKind::MAP(items) |
+            crate::reader::ExprKind::SET(items) => self.exprs(items),
+
+            // quoted forms aren't evaluated, so anything goes
+            crate::reader::ExprKind::QUOTE(_) |
+            crate::reader::ExprKind::SYMBOL(_) |
+            crate::reader::ExprKind::KEYWORD(_) |
+            crate::reader::ExprKind::LITERAL(_) => Ok(()),
+        }
+    }
+
+    fn exprs(&self, exprs: &[crate::reader::Expr]) -> Result<(), Box<crate::error::SophieError>> {
+        for expr in exprs.iter() {
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self,
+            list: &crate::reader::Expr,
+            items: &[crate::reader::Expr]) -> Result<(), Box<crate::error::SophieError>> {
+        let (head, args) = match items.split_first() {
+            Some(split) => split,
+            None => return Err(self.error(list, "Can't evaluate an empty list.".to_string(), None))
+        };
+
+        let name = match &head.kind {
+            crate::reader::ExprKind::SYMBOL(name) => name.as_str(),
+
+            // a call to whatever the head evaluates to
+            _ => return self.exprs(items)
+        };
+
+        if let Some((_, min, max)) = ARITIES.iter().find(|(n, _, _)| *n == name) {
+            let count = args.len();
+            if count < *min || max.is_some_and(|max| count > max) {
+                let message = format!("'{}' expects {}, got {}.", name, expected(*min, *max), count);
+                return Err(self.error(head, message, usage(name)));
+            }
+        }
+
+        match name {
+            "def" => {
+                if !is_symbol(&args[0]) {
+                    let message = format!("'def' expects a symbol to define, got {}.",
+                                          describe(&args[0]));
+                    return Err(self.error(&args[0], message, usage(name)));
+                }
+                self.unreserved(&args[0])?;
+                self.expr(&args[1])
+            },
+
+            "let" | "loop" => {
+                let bindings = self.vector(name, &args[0])?;
+                if !bindings.len().is_multiple_of(2) {
+                    return Err(self.error(&args[0],
+                                          format!("Expected a value for every {} binding.", name),
+                                          usage(name)));
+                }
+                for pair in bindings.chunks(2) {
+                    if !is_symbol(&pair[0]) {
+                        let message = format!("Let bindings must be symbols, not {}.",
+                                              describe(&pair[0]));
+                        return Err(self.error(&pair[0], message, usage(name)));
+                    }
+                    self.unreserved(&pair[0])?;
+                    self.expr(&pair[1])?;
+                }
+                self.exprs(&args[1..])
+            },
+
+            "fn" => {
+                let params = self.vector(name, &args[0])?;
+                for param in params.iter() {
+                    if !is_symbol(param) {
+                        let message = format!("Function parameters must be symbols, not {}.",
+                                              describe(param));
+                        return Err(self.error(param, message, usage(name)));
+                    }
+                    self.unreserved(param)?;
+                }
+                // a function's arity has to fit in a byte
+                if let Some(param) = params.get(MAX_PARAMS) {
+                    let message = format!("Can't have more than {} parameters.", MAX_PARAMS);
+                    return Err(self.error(param, message, None));
+                }
+                self.exprs(&args[1..])
+            },
+
+            "cond" if !args.len().is_multiple_of(2) =>
+                Err(self.error(head,
+                               "Expected an even number of forms after cond.".to_string(),
+                               Some("each test needs an expression after it"))),
+
+            _ => self.exprs(args)
+        }
+    }
+
+    // the items of `expr`, which `form` needs to be a vector
+    fn vector<'e>(&self,
+                  form: &str,
+                  expr: &'e crate::reader::Expr) -> Result<&'e [crate::reader::Expr], Box<crate::error::SophieError>> {
+        match &expr.kind {
+            crate::reader::ExprKind::VECTOR(items) => Ok(items),
+            _ => {
+                let message = format!("Expected '[' after {}, got {}.", form, describe(expr));
+                Err(self.error(expr, message, usage(form)))
+            }
+        }
+    }
+
+    // `expr` is a symbol being bound. special forms and builtin ops
+    // can't be, since they'd never be looked up
+    fn unreserved(&self, expr: &crate::reader::Expr) -> Result<(), Box<crate::error::SophieError>> {
+        match &expr.kind {
+            crate::reader::ExprKind::SYMBOL(name) if crate::compiler::is_reserved(name) =>
+                Err(self.error(expr,
+                               format!("Can't bind '{}', it already means something.", name),
+                               None)),
+            _ => Ok(())
+        }
+    }
+
+    fn error(&self,
+             expr: &crate::reader::Expr,
+             message: String,
+             help: Option<&str>) -> Box<crate::error::SophieError> {
+        let err = crate::error::compile_error(message,
+                                              self.file,
+                                              self.source,
+                                              &expr.span);
+        match help {
+            Some(help) => err.with_help(help),
+            None => err
+        }
+    }
+}
+
+fn is_symbol(expr: &crate::reader::Expr) -> bool {
+    matches!(expr.kind, crate::reader::ExprKind::SYMBOL(_))
+}
+
+// "2 arguments", "2 or 3 arguments", "at least 1 argument"
+fn expected(min: usize, max: Option<usize>) -> String {
+    let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+    match max {
+        Some(max) if max == min => format!("{} {}", min, plural(min)),
+        Some(max) if max == min + 1 => format!("{} or {} {}", min, max, plural(max)),
+        Some(max) => format!("between {} and {} {}", min, max, plural(max)),
+        None => format!("at least {} {}", min, plural(min)),
+    }
+}
+
+// what the special forms look like, for the help line
+fn usage(name: &str) -> Option<&'static str> {
+    match name {
+        "if" => Some("ifs look like (if condition then else?)"),
+        "def" => Some("definitions look like (def name value)"),
+        "let" => Some("bindings look like (let [name value ...] body)"),
+        "loop" => Some("loops look like (loop [name value ...] body), with a (recur ...) in the body"),
+        "fn" => Some("functions look like (fn [arg ...] body)"),
+        _ => None
+    }
+}
+
+// "a vector", "the keyword :a", for saying what we got instead
+fn describe(expr: &crate::reader::Expr) -> String {
+    match &expr.kind {
+        crate::reader::ExprKind::LIST(_) => "a list".to_string(),
+        crate::reader::ExprKind::VECTOR(_) => "a vector".to_string(),
+        crate::reader::ExprKind::MAP(_) => "a map".to_string(),
+        crate::reader::ExprKind::SET(_) => "a set".to_string(),
+        crate::reader::ExprKind::QUOTE(_) => "a quoted form".to_string(),
+        crate::reader::ExprKind::SYMBOL(name) => format!("the symbol {}", name),
+        crate::reader::ExprKind::KEYWORD(name) => format!("the keyword :{}", name),
+        crate::reader::ExprKind::LITERAL(crate::reader::Literal::INT(n)) => format!("the number {}", n),
+        crate::reader::ExprKind::LITERAL(crate::reader::Literal::FLOAT(n)) => format!("the number {}", n),
+        crate::reader::ExprKind::LITERAL(crate::reader::Literal::STRING(_)) => "a string".to_string(),
+        crate::reader::ExprKind::LITERAL(crate::reader::Literal::BOOL(b)) => b.to_string(),
+        crate::reader::ExprKind::LITERAL(crate::reader::Literal::NIL) => "nil".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn validate(source: &str) -> Result<(), Box<crate::error::SophieError>> {
+        let forms = crate::reader::read(source, "<test>", 0).unwrap();
+        super::validate(&forms, "<test>", source)
+    }
+
+    // `source` is rejected with `message`, and the caret sits under
+    // `length` chars from `column` of `line`
+    fn rejects(source: &str, message: &str, line: u32, column: usize, length: usize) {
+        let err = match validate(source) {
+            Ok(()) => panic!("{} should have been rejected", source),
+            Err(err) => err
+        };
+        assert_eq!(err.message, message, "for {}", source);
+        assert_eq!((err.line, err.column, err.length), (line, column, length), "for {}", source);
+    }
+
+    #[test]
+    fn special_form_arities() {
+        rejects("(if 1)", "'if' expects 2 or 3 arguments, got 1.", 1, 2, 2);
+        rejects("(if 1 2 3 4)", "'if' expects 2 or 3 arguments, got 4.", 1, 2, 2);
+        rejects("(def x)", "'def' expects 2 arguments, got 1.", 1, 2, 3);
+        rejects("(def x 1 2)", "'def' expects 2 arguments, got 3.", 1, 2, 3);
+        rejects("(let)", "'let' expects at least 1 argument, got 0.", 1, 2, 3);
+        rejects("(loop)", "'loop' expects at least 1 argument, got 0.", 1, 2, 4);
+        rejects("(fn)", "'fn' expects at least 1 argument, got 0.", 1, 2, 2);
+        rejects("(when)", "'when' expects at least 1 argument, got 0.", 1, 2, 4);
+        rejects("(unless)", "'unless' expects at least 1 argument, got 0.", 1, 2, 6);
+    }
+
+    #[test]
+    fn builtin_arities() {
+        for op in ["-", "/", "=", "<", ">", "<=", ">="].iter() {
+            let message = format!("'{}' expects at least 1 argument, got 0.", op);
+            rejects(&format!("({})", op), &message, 1, 2, op.len());
+        }
+        for op in ["not", "len", "print"].iter() {
+            rejects(&format!("({})", op),
+                    &format!("'{}' expects 1 argument, got 0.", op), 1, 2, op.len());
+            rejects(&format!("({} 1 2)", op),
+                    &format!("'{}' expects 1 argument, got 2.", op), 1, 2, op.len());
+        }
+    }
+
+    #[test]
+    fn special_form_shapes() {
+        rejects("()", "Can't evaluate an empty list.", 1, 1, 2);
+        rejects("(def 1 2)", "'def' expects a symbol to define, got the number 1.", 1, 6, 1);
+        rejects("(let (a 1) a)", "Expected '[' after let, got a list.", 1, 6, 5);
+        rejects("(loop [a] a)", "Expected a value for every loop binding.", 1, 7, 3);
+        rejects("(let [:a 1] 2)", "Let bindings must be symbols, not the keyword :a.", 1, 7, 2);
+        rejects("(fn x 1)", "Expected '[' after fn, got the symbol x.", 1, 5, 1);
+        rejects("(fn [a \"b\"] a)", "Function parameters must be symbols, not a string.", 1, 8, 3);
+        rejects("(cond 1 2 3)", "Expected an even number of forms after cond.", 1, 2, 4);
+
+        let params: Vec<String> = (0..256).map(|i| format!("p{}", i)).collect();
+        let source = format!("(fn [{}] 1)", params.join(" "));
+        let column = source.find("p255").unwrap() + 1;
+        rejects(&source, "Can't have more than 255 parameters.", 1, column, 4);
+    }
+
+    #[test]
+    fn reserved_names_cant_be_bound() {
+        rejects("(def if 1)", "Can't bind 'if', it already means something.", 1, 6, 2);
+        rejects("(def + 2)", "Can't bind '+', it already means something.", 1, 6, 1);
+        rejects("(def not 5)", "Can't bind 'not', it already means something.", 1, 6, 3);
+        rejects("(let [a 1 len 2] a)", "Can't bind 'len', it already means something.", 1, 11, 3);
+        rejects("(fn [x recur] x)", "Can't bind 'recur', it already means something.", 1, 8, 5);
+    }
+
+    #[test]
+    fn errors_inside_other_forms() {
+        rejects("(do 1\n  (+ 1 (if 2)))", "'if' expects 2 or 3 arguments, got 1.", 2, 9, 2);
+        rejects("[{:a (let)}]", "'let' expects at least 1 argument, got 0.", 1, 7, 3);
+    }
+
+    #[test]
+    fn accepts_well_formed_code() {
+        let sources = ["(+)", "(*)", "(do)", "(cond)", "(and)", "(or)", "(recur)",
+                       "(if 1 2)", "(def ifs 1)", "(let [] 1)", "(let [a 1 a 2] a)",
+                       "(fn [] 1)", "(cond 1 2 :else 3)", "'(if)", "((fn [x] x) 1)"];
+        for source in sources.iter() {
+            if let Err(err) = validate(source) {
+                panic!("{} was rejected: {}", source, err);
+            }
+        }
+    }
+}
@@ -1,24 +1,85 @@
-#[macro_use]
+use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
+// what the compiler puts in a chunk's constant table. anything that
+// lives on the heap is already reference counted, so turning one of
+// these into a ValueType at runtime is just a refcount bump
 #[derive(Debug)]
+#[derive(Clone)]
 pub enum ConstantType {
     INT(i64),
     FLOAT(f64),
-    STRING(String),
-    SYMBOL(String)
+    STRING(Rc<str>),
+    SYMBOL(Rc<str>),
+    KEYWORD(Rc<str>),
+    FUNCTION(Rc<Function>),
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub arity: u8,
+    pub upvalue_count: u8,
+    pub chunk: crate::chunk::Chunk,
+    pub name: Option<String>,
+
+    // the file it was read from, and what was in it
+    pub file: Rc<str>,
+    pub source: Rc<str>,
+}
+
+// a Function plus the variables it closed over. every function value
+// the VM handles at runtime is one of these
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+pub type NativeFn = fn(&mut crate::vm::VM, &[ValueType]) -> Result<ValueType, String>;
+
+// a function implemented in rust
+#[derive(Debug)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+// a captured variable. it points into the VM stack for as long as the
+// variable's slot is live; when the slot goes away, the value moves in
+// here
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(ValueType),
 }
 
+// numbers, bools and nil are stored inline. everything else is a
+// pointer to a reference counted heap object, so values can be copied
+// around freely and outlive the chunk that created them
 #[derive(Debug)]
 #[derive(Clone)]
-pub enum ValueType<'a> {
+pub enum ValueType {
     BOOL(bool),
     NIL,
     FLOAT(f64),
     INT(i64),
-    STRING(&'a String),
-    SYMBOL(&'a String),
+    STRING(Rc<str>),
+    SYMBOL(Rc<str>),
+
+    // interned, so two keywords are equal only if they're the same
+    // pointer
+    KEYWORD(Rc<str>),
+    FUNCTION(Rc<Function>),
+    CLOSURE(Rc<Closure>),
+    NATIVE(Rc<Native>),
+    VECTOR(Rc<crate::collections::Vector>),
+    MAP(Rc<crate::collections::Map>),
+    SET(Rc<crate::collections::Set>),
 }
 
+#[derive(Debug)]
 pub struct Values {
     pub values: Vec<ConstantType>
 }
@@ -27,35 +88,35 @@ pub struct Values {
 #[macro_export]
 macro_rules! bool_val {
     ($value:expr) => {
-        crate::value::ValueType::BOOL($value)
+        $crate::value::ValueType::BOOL($value)
     };
 }
 
 #[macro_export]
 macro_rules! nil_val {
     () => {
-        crate::value::ValueType::NIL
+        $crate::value::ValueType::NIL
     };
 }
 
 #[macro_export]
 macro_rules! float_val {
     ($value:expr) => {
-        crate::value::ValueType::FLOAT($value)
+        $crate::value::ValueType::FLOAT($value)
     };
 }
 
 #[macro_export]
 macro_rules! int_val {
     ($value:expr) => {
-        crate::value::ValueType::INT($value)
+        $crate::value::ValueType::INT($value)
     };
 }
 
 #[macro_export]
 macro_rules! string_val {
     ($value:expr) => {
-        crate::value::ValueType::STRING($value)
+        $crate::value::ValueType::STRING($value)
     };
 }
 
@@ -90,8 +151,9 @@ macro_rules! as_int {
 
 macro_rules! as_string {
     ($value:expr) => {{
-        match $value {
-            crate::value::ValueType::STRING(n) =>  n,
+        match &$value {
+            crate::value::ValueType::STRING(n) =>  &n[..],
+            crate::value::ValueType::SYMBOL(n) =>  &n[..],
             _ => ""
         }
     }}
@@ -117,6 +179,7 @@ macro_rules! is_nil {
 }
 
 
+#[allow(unused_macros)]
 macro_rules! is_float {
     ($value:expr) => {{
         match $value {
@@ -126,6 +189,7 @@ macro_rules! is_float {
     }}
 }
 
+#[allow(unused_macros)]
 macro_rules! is_int {
     ($value:expr) => {{
         match $value {
@@ -145,6 +209,17 @@ macro_rules! is_int {
 //     }}
 // }
 
+pub fn init_function(name: Option<String>, file: Rc<str>, source: Rc<str>) -> Function {
+    Function {
+        arity: 0,
+        upvalue_count: 0,
+        chunk: crate::chunk::init_chunk(),
+        name,
+        file,
+        source,
+    }
+}
+
 #[allow(dead_code)]
 pub fn init_values() -> Values {
     Values {
@@ -152,6 +227,43 @@ pub fn init_values() -> Values {
     }
 }
 
+// what a constant is deduplicated by. strings, symbols and keywords
+// are interned, so they're compared by address
+#[derive(Debug)]
+#[derive(PartialEq, Eq, Hash)]
+pub enum ConstantKey {
+    INT(i64),
+    FLOAT(u64),
+    STRING(crate::interner::Id),
+    SYMBOL(crate::interner::Id),
+    KEYWORD(crate::interner::Id),
+}
+
+impl ConstantType {
+    // None for functions, which are never shared
+    pub fn key(&self) -> Option<ConstantKey> {
+        match self {
+            ConstantType::INT(n) => Some(ConstantKey::INT(*n)),
+            ConstantType::FLOAT(n) => Some(ConstantKey::FLOAT(n.to_bits())),
+            ConstantType::STRING(s) => Some(ConstantKey::STRING(crate::interner::id(s))),
+            ConstantType::SYMBOL(s) => Some(ConstantKey::SYMBOL(crate::interner::id(s))),
+            ConstantType::KEYWORD(k) => Some(ConstantKey::KEYWORD(crate::interner::id(k))),
+            ConstantType::FUNCTION(_) => None,
+        }
+    }
+
+    pub fn to_value(&self) -> ValueType {
+        match self {
+            ConstantType::INT(n) => ValueType::INT(*n),
+            ConstantType::FLOAT(n) => ValueType::FLOAT(*n),
+            ConstantType::STRING(s) => ValueType::STRING(Rc::clone(s)),
+            ConstantType::SYMBOL(s) => ValueType::SYMBOL(Rc::clone(s)),
+            ConstantType::KEYWORD(k) => ValueType::KEYWORD(Rc::clone(k)),
+            ConstantType::FUNCTION(f) => ValueType::FUNCTION(Rc::clone(f)),
+        }
+    }
+}
+
 impl Values {
     pub fn write_values(&mut self, value: ConstantType) -> usize {
         self.values.push(value);
@@ -167,7 +279,153 @@ pub fn print_value(value: &ValueType) {
         ValueType::BOOL(b) =>
             if *b {print!("true")} else {print!("false")},
         ValueType::STRING(_) => print!("{}", as_string!(*value)),
-        ValueType::SYMBOL(_) => print!("{} (sym)", as_string!(*value))
+        ValueType::SYMBOL(_) => print!("{} (sym)", as_string!(*value)),
+        ValueType::KEYWORD(k) => print!(":{}", k),
+        ValueType::FUNCTION(f) => print_function(f),
+        ValueType::CLOSURE(c) => print_function(&c.function),
+        ValueType::NATIVE(n) => print!("<native fn {}>", n.name),
+        ValueType::VECTOR(v) => {
+            print!("[");
+            print_values(v.iter());
+            print!("]");
+        },
+        ValueType::MAP(m) => {
+            print!("{{");
+            for (i, (k, v)) in m.iter().enumerate() {
+                if i > 0 {
+                    print!(", ");
+                }
+                print_value(k);
+                print!(" ");
+                print_value(v);
+            }
+            print!("}}");
+        },
+        ValueType::SET(s) => {
+            print!("#{{");
+            print_values(s.iter());
+            print!("}}");
+        },
+    }
+}
+
+// space separated
+fn print_values<'a>(values: impl Iterator<Item = &'a ValueType>) {
+    for (i, v) in values.enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print_value(v);
     }
+}
+
+fn print_function(function: &Function) {
+    print!("{}", function_name(function))
 
 }
+
+// for error messages
+pub fn type_name(value: &ValueType) -> &'static str {
+    match value {
+        ValueType::BOOL(_) => "bool",
+        ValueType::NIL => "nil",
+        ValueType::FLOAT(_) => "float",
+        ValueType::INT(_) => "int",
+        ValueType::STRING(_) => "string",
+        ValueType::SYMBOL(_) => "symbol",
+        ValueType::KEYWORD(_) => "keyword",
+        ValueType::FUNCTION(_) | ValueType::CLOSURE(_) | ValueType::NATIVE(_) => "function",
+        ValueType::VECTOR(_) => "vector",
+        ValueType::MAP(_) => "map",
+        ValueType::SET(_) => "set",
+    }
+}
+
+pub fn function_name(function: &Function) -> String {
+    match &function.name {
+        Some(name) => format!("<fn {}>", name),
+        None => "<fn>".to_string()
+    }
+}
+
+// functions are only equal to themselves, and symbols and keywords
+// are interned so they're compared by address; everything else
+// compares by value
+pub fn values_equal(l: &ValueType,
+                    r: &ValueType) -> bool {
+
+    match (l,r) {
+        (ValueType::BOOL(lv), ValueType::BOOL(rv)) => { lv == rv },
+        (ValueType::NIL, ValueType::NIL) => true,
+        (ValueType::INT(lv), ValueType::INT(rv)) => { lv == rv },
+        (ValueType::FLOAT(lv), ValueType::FLOAT(rv)) => { lv == rv },
+        (ValueType::STRING(lv), ValueType::STRING(rv)) => { lv == rv },
+        (ValueType::SYMBOL(lv), ValueType::SYMBOL(rv)) => Rc::ptr_eq(lv, rv),
+        (ValueType::KEYWORD(lv), ValueType::KEYWORD(rv)) => Rc::ptr_eq(lv, rv),
+        (ValueType::FUNCTION(lv), ValueType::FUNCTION(rv)) => Rc::ptr_eq(lv, rv),
+        (ValueType::CLOSURE(lv), ValueType::CLOSURE(rv)) => Rc::ptr_eq(lv, rv),
+        (ValueType::NATIVE(lv), ValueType::NATIVE(rv)) => Rc::ptr_eq(lv, rv),
+        (ValueType::VECTOR(lv), ValueType::VECTOR(rv)) => {
+            lv.len() == rv.len()
+                && lv.iter().zip(rv.iter()).all(|(l, r)| values_equal(l, r))
+        },
+        (ValueType::MAP(lv), ValueType::MAP(rv)) => {
+            lv.len() == rv.len()
+                && lv.iter().all(|(k, v)| match rv.get(k) {
+                    Some(other) => values_equal(v, other),
+                    None => false
+                })
+        },
+        (ValueType::SET(lv), ValueType::SET(rv)) => {
+            lv.len() == rv.len() && lv.iter().all(|v| rv.contains(v))
+        },
+        (_,_) => false
+    }
+
+}
+
+// consistent with `values_equal`: equal values hash the same
+pub fn hash_value(value: &ValueType) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    hash_into(value, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_into<H: Hasher>(value: &ValueType, state: &mut H) {
+    // so that, say, 1 and "1" don't collide
+    std::mem::discriminant(value).hash(state);
+
+    match value {
+        ValueType::BOOL(b) => b.hash(state),
+        ValueType::NIL => (),
+        ValueType::INT(n) => n.hash(state),
+
+        // 0.0 == -0.0, so they have to hash the same
+        ValueType::FLOAT(n) => (if *n == 0.0 { 0.0 } else { *n }).to_bits().hash(state),
+        ValueType::STRING(s) => s.hash(state),
+        ValueType::SYMBOL(s) => crate::interner::id(s).hash(state),
+        ValueType::KEYWORD(k) => crate::interner::id(k).hash(state),
+        ValueType::FUNCTION(f) => Rc::as_ptr(f).hash(state),
+        ValueType::CLOSURE(c) => Rc::as_ptr(c).hash(state),
+        ValueType::NATIVE(n) => Rc::as_ptr(n).hash(state),
+        ValueType::VECTOR(v) => {
+            v.len().hash(state);
+            for item in v.iter() {
+                hash_into(item, state);
+            }
+        },
+
+        // maps and sets don't have an order, so combine their
+        // entries' hashes in a way that doesn't care about order
+        ValueType::MAP(m) => {
+            let sum = m.iter().fold(0u64, |sum, (k, v)| {
+                sum.wrapping_add(hash_value(k) ^ hash_value(v).rotate_left(1))
+            });
+            sum.hash(state);
+        },
+        ValueType::SET(s) => {
+            let sum = s.iter().fold(0u64, |sum, v| sum.wrapping_add(hash_value(v)));
+            sum.hash(state);
+        },
+    }
+}
@@ -1,71 +1,131 @@
 extern crate num_derive;
 use num::{FromPrimitive};
-use std::collections::{HashMap};
+use std::cell::RefCell;
+use std::rc::Rc;
 
-pub struct VM<'a> {
-    // pub chunk: Option<crate::chunk::Chunk>,
-    pub ip: usize,
-    pub stack: Vec<crate::value::ValueType<'a>>,
-    pub symbols: HashMap<String, crate::value::ValueType<'a>>
+// deep enough for reasonable recursion, shallow enough that runaway
+// recursion fails quickly
+const FRAMES_MAX: usize = 1024;
+
+pub struct VM {
+    pub frames: Vec<CallFrame>,
+    pub stack: Vec<crate::value::ValueType>,
+    pub globals: crate::globals::Globals,
+
+    // the names of the files we've been given code from, indexed by
+    // FileId
+    pub files: Vec<Rc<str>>,
+
+    // upvalues still pointing into the stack, ordered by stack slot
+    pub open_upvalues: Vec<Rc<RefCell<crate::value::Upvalue>>>,
+
+    pub heap: crate::alloc::Heap,
+    pub interner: crate::interner::Interner,
+
+    // told about every instruction before it runs, if set
+    pub tracer: Option<Box<dyn crate::debug::Tracer>>,
 }
 
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+// a function invocation in progress. `slots` is the index into the
+// VM stack of the function's slot 0 (the callee itself); its
+// arguments and locals follow
+pub struct CallFrame {
+    pub closure: Rc<crate::value::Closure>,
+    pub ip: usize,
+    pub slots: usize,
 }
 
-impl<'a> VM<'a> {
-    pub fn peek(&'a self, ix: usize) -> &'a crate::value::ValueType {
+// the value the top level evaluated to, or what went wrong
+pub type InterpretResult = Result<crate::value::ValueType, Box<crate::error::SophieError>>;
+
+impl VM {
+    #[allow(dead_code)]
+    pub fn peek(&self, ix: usize) -> &crate::value::ValueType {
         self.stack.get(self.stack.len()-(1 + ix)).unwrap()
     }
 }
 
 macro_rules! read_byte {
-    ($vm:expr, $chunk:expr) => {{
-        $vm.ip += 1;
-        $chunk.code[$vm.ip - 1]
+    ($vm:expr) => {{
+        let frame = $vm.frames.last_mut().unwrap();
+        frame.ip += 1;
+        frame.closure.function.chunk.code[frame.ip - 1]
+    }};
+}
+
+// a big-endian operand of `n` bytes
+macro_rules! read_operand {
+    ($vm:expr, $n:expr) => {{
+        let mut operand: usize = 0;
+        for _ in 0..$n {
+            operand = (operand << 8) | read_byte!($vm) as usize;
+        }
+        operand
+    }};
+}
+
+macro_rules! read_short {
+    ($vm:expr) => {
+        read_operand!($vm, 2)
+    };
+}
+
+// a constant index: one byte, or three if `$instruction` is the _LONG
+// form
+macro_rules! read_index {
+    ($vm:expr, $instruction:expr, $long:ident) => {{
+        if matches!($instruction, Some(crate::chunk::Opcode::$long)) {
+            read_operand!($vm, 3)
+        } else {
+            read_byte!($vm) as usize
+        }
     }};
 }
 
+// (< a b c ...): the next byte says how many values to take off the
+// stack, and each is compared with the one after it
 macro_rules! bool_op {
     ($vm:expr, $op:tt) => {{
+        let n = read_byte!($vm) as usize;
+        let operands = $vm.stack.split_off($vm.stack.len() - n);
 
-        let l = $vm.stack.pop().unwrap();
-        let r = $vm.stack.pop().unwrap();
-
-        $vm.stack.push(
-            match (l, r) {
+        let mut result = true;
+        for pair in operands.windows(2) {
+            let holds = match (&pair[0], &pair[1]) {
                 (crate::value::ValueType::INT(lv),
                  crate::value::ValueType::INT(rv)) => {
-                    bool_val!(lv $op rv)
+                    lv $op rv
                 }
 
                 (crate::value::ValueType::FLOAT(lv),
                  crate::value::ValueType::INT(rv)) => {
-                    bool_val!(lv $op (rv as f64))
+                    *lv $op (*rv as f64)
                 }
 
                 (crate::value::ValueType::INT(lv),
                  crate::value::ValueType::FLOAT(rv)) => {
-                    bool_val!((lv as f64) $op rv)
+                    (*lv as f64) $op *rv
                 }
                 (crate::value::ValueType::FLOAT(lv),
                  crate::value::ValueType::FLOAT(rv)) => {
-                    bool_val!(lv $op rv)
+                    lv $op rv
                 }
                 _ => {
-                    runtime_error("Operands to bool ops must be numbers");
-                    return InterpretResult::RuntimeError
+                    return Err($vm.runtime_error(
+                        "Operands to bool ops must be numbers".to_string()))
                 }
-            }
-        );
+            };
+            result = result && holds;
+        }
+
+        $vm.stack.push(bool_val!(result));
     }};
 }
 
-// may push a float or an int
+// may push a float or an int. ints use `$checked`, so overflowing
+// (or dividing by zero) is an error rather than a panic
 macro_rules! number_op {
-    ($vm:expr, $op:tt) => {{
+    ($vm:expr, $checked:ident, $op:tt) => {{
         let r = $vm.stack.pop().unwrap();
         let l = $vm.stack.pop().unwrap();
 
@@ -73,7 +133,15 @@ macro_rules! number_op {
             match (l, r) {
                 (crate::value::ValueType::INT(lv),
                  crate::value::ValueType::INT(rv)) => {
-                    int_val!(lv $op rv)
+                    match lv.$checked(rv) {
+                        Some(n) => int_val!(n),
+
+                        // only dividing can fail with a zero
+                        None if rv == 0 => return Err($vm.runtime_error(
+                            "Division by zero.".to_string())),
+                        None => return Err($vm.runtime_error(
+                            "Integer overflow.".to_string()))
+                    }
                 }
 
                 (crate::value::ValueType::FLOAT(lv),
@@ -90,8 +158,8 @@ macro_rules! number_op {
                     float_val!(lv $op rv)
                 }
                 _ => {
-                    runtime_error("Operands to number ops must be numbers");
-                    return InterpretResult::RuntimeError
+                    return Err($vm.runtime_error(
+                        "Operands to number ops must be numbers".to_string()))
                 }
             }
         );
@@ -99,64 +167,400 @@ macro_rules! number_op {
 }
 
 
-pub fn init_vm<'a>() -> VM<'a> {
-    VM {
-        ip: 0,
+pub fn init_vm() -> VM {
+    let mut vm = VM {
+        frames: Vec::new(),
         stack: Vec::new(),
-        symbols: HashMap::new()
-    }
+        globals: crate::globals::init_globals(),
+        files: Vec::new(),
+        open_upvalues: Vec::new(),
+        heap: crate::alloc::init_heap(),
+        interner: crate::interner::init_interner(),
+        tracer: None,
+    };
+    crate::native::define_natives(&mut vm);
+    vm
 }
 
+#[allow(dead_code)]
 pub fn free_vm() {}
 
-impl<'a> VM<'a> {
-    pub fn interpret(&'a mut self,
-                     source: &str,
-                     chunk: &'a mut crate::chunk::Chunk) -> InterpretResult {
-        if !crate::compiler::compile(source, chunk) {
-            return InterpretResult::CompileError
+impl VM {
+    // can be called repeatedly; definitions from earlier calls stick
+    // around
+    pub fn interpret(&mut self, source: &str, file: &str) -> InterpretResult {
+        let file_id = self.file_id(file);
+        let function = crate::compiler::compile_script(source,
+                                                          file,
+                                                          file_id,
+                                                          &mut self.interner,
+                                                          &mut self.globals)?;
+
+        let closure = Rc::new(crate::value::Closure {
+            function: Rc::new(function),
+            upvalues: Vec::new(),
+        });
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: 0,
+        });
+
+        let result = self.run();
+        if result.is_err() {
+            self.reset_stack();
+        }
+        result
+    }
+
+    // the same id every time for the same file name, so the repl's
+    // lines all share one
+    pub fn file_id(&mut self, file: &str) -> crate::scanner::FileId {
+        match self.files.iter().position(|f| &f[..] == file) {
+            Some(ix) => ix as crate::scanner::FileId,
+            None => {
+                self.files.push(file.into());
+                (self.files.len() - 1) as crate::scanner::FileId
+            }
+        }
+    }
+
+    fn call_value(&mut self, argc: u8) -> Result<(), Box<crate::error::SophieError>> {
+        let callee = self.stack[self.stack.len() - 1 - argc as usize].clone();
+        match callee {
+            crate::value::ValueType::CLOSURE(c) => self.call(c, argc),
+            crate::value::ValueType::NATIVE(n) => self.call_native(&n, argc),
+            crate::value::ValueType::KEYWORD(k) => self.call_keyword(&k, argc),
+            _ => Err(self.runtime_error("Can only call functions.".to_string()))
+        }
+    }
+
+    // a call whose value is immediately returned. calling a closure
+    // replaces the running function's frame rather than pushing a new
+    // one, so recursion in tail position runs in constant space
+    fn tail_call_value(&mut self, argc: u8) -> Result<(), Box<crate::error::SophieError>> {
+        let callee_slot = self.stack.len() - 1 - argc as usize;
+        let closure = match &self.stack[callee_slot] {
+            crate::value::ValueType::CLOSURE(c) => Rc::clone(c),
+
+            // natives don't get a frame anyway, so the code after us
+            // returns whatever they leave behind
+            _ => return self.call_value(argc)
         };
 
-        self.ip = 0;
+        if argc != closure.function.arity {
+            return self.call(closure, argc);
+        }
+
+        // slide the callee and its arguments down over our own frame
+        let slots = self.frames.last().unwrap().slots;
+        self.close_upvalues(slots);
+        self.stack.drain(slots..callee_slot);
+
+        let frame = self.frames.last_mut().unwrap();
+        frame.closure = closure;
+        frame.ip = 0;
+        Ok(())
+    }
+
+    // (:k m) looks :k up in m, and (:k m default) gives default if
+    // it's not there
+    fn call_keyword(&mut self, keyword: &Rc<str>, argc: u8) -> Result<(), Box<crate::error::SophieError>> {
+        if argc != 1 && argc != 2 {
+            let mut err = self.runtime_error(
+                format!("Expected 1 or 2 arguments but got {}.", argc));
+            err.push_note(format!("in call to keyword :{}", keyword));
+            return Err(err);
+        }
+
+        let default = if argc == 2 {
+            self.stack.pop().unwrap()
+        } else {
+            crate::value::ValueType::NIL
+        };
+        let coll = self.stack.pop().unwrap();
+
+        let key = crate::value::ValueType::KEYWORD(Rc::clone(keyword));
+        let found = match &coll {
+            crate::value::ValueType::MAP(m) => m.get(&key).cloned(),
+            crate::value::ValueType::SET(s) if s.contains(&key) => Some(key),
+            _ => None
+        };
 
-        self.run(chunk)
+        // the callee
+        self.stack.pop();
+        self.stack.push(found.unwrap_or(default));
+        Ok(())
     }
 
-    fn run(&'a mut self, chunk: &'a mut crate::chunk::Chunk) -> InterpretResult {
+    fn call(&mut self, closure: Rc<crate::value::Closure>, argc: u8) -> Result<(), Box<crate::error::SophieError>> {
+        if argc != closure.function.arity {
+            let mut err = self.runtime_error(
+                format!("Expected {} arguments but got {}.",
+                        closure.function.arity, argc));
+            err.push_note(format!("in call to {}",
+                                  crate::value::function_name(&closure.function)));
+            return Err(err);
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error("Stack overflow.".to_string()));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - 1 - argc as usize,
+        });
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: &crate::value::Native, argc: u8) -> Result<(), Box<crate::error::SophieError>> {
+        if argc != native.arity {
+            let mut err = self.runtime_error(
+                format!("Expected {} arguments but got {}.",
+                        native.arity, argc));
+            err.push_note(format!("in call to <native fn {}>", native.name));
+            return Err(err);
+        }
+
+        let args = self.stack.split_off(self.stack.len() - argc as usize);
+        match (native.function)(self, &args) {
+            Ok(v) => {
+                // the callee
+                self.stack.pop();
+                self.stack.push(v);
+                Ok(())
+            },
+            Err(msg) => {
+                let mut err = self.runtime_error(msg);
+                err.push_note(format!("in <native fn {}>", native.name));
+                Err(err)
+            }
+        }
+    }
+
+    pub fn define_native(&mut self,
+                         name: &'static str,
+                         arity: u8,
+                         function: crate::value::NativeFn) {
+        let slot = self.globals.slot(&self.interner.intern(name));
+        self.globals.values[slot] = Some(crate::value::ValueType::NATIVE(Rc::new(
+            crate::value::Native {
+                name,
+                arity,
+                function,
+            })));
+    }
+
+    // reuse an open upvalue if some other closure already captured
+    // this slot, so they all see the same variable
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<crate::value::Upvalue>> {
+        let mut ix = self.open_upvalues.len();
+        while ix > 0 {
+            let upvalue = Rc::clone(&self.open_upvalues[ix - 1]);
+            if let crate::value::Upvalue::Open(s) = *upvalue.borrow() {
+                if s == slot {
+                    return Rc::clone(&upvalue);
+                }
+                if s < slot {
+                    break;
+                }
+            }
+            ix -= 1;
+        }
+
+        let upvalue = self.heap.alloc_upvalue(crate::value::Upvalue::Open(slot));
+        self.open_upvalues.insert(ix, Rc::clone(&upvalue));
+        upvalue
+    }
+
+    // move every upvalue at or above `last` off the stack
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                crate::value::Upvalue::Open(s) => s,
+                crate::value::Upvalue::Closed(_) => unreachable!(),
+            };
+            if slot < last {
+                break;
+            }
+            let v = self.stack[slot].clone();
+            *upvalue.borrow_mut() = crate::value::Upvalue::Closed(v);
+            self.open_upvalues.pop();
+        }
+    }
+
+    // an error at the instruction we just read, with a backtrace
+    // through every call still in progress. calls made from tail
+    // position replaced their caller's frame, so those callers don't
+    // show up
+    fn runtime_error(&self, message: String) -> Box<crate::error::SophieError> {
+        let frame = self.frames.last().unwrap();
+        let function = &frame.closure.function;
+        let span = function.chunk.span_for(frame.ip - 1);
+        let mut err = crate::error::runtime_error(message, &function.file, &function.source, &span);
+
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            let function = &frame.closure.function;
+
+            // the bottom frame is the top level of the script
+            let name = if i == 0 {
+                "<script>".to_string()
+            } else {
+                crate::value::function_name(function)
+            };
+
+            let span = function.chunk.span_for(frame.ip - 1);
+            err.backtrace.push(crate::error::Frame {
+                function: name,
+                file: function.file.to_string(),
+                line: span.line,
+                col: span.col,
+            });
+        }
+        err
+    }
+
+    // throw away whatever a failed run left behind, so the next
+    // `interpret` starts clean. globals survive
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn run(&mut self) -> InterpretResult {
         loop {
-            // debug
-            print!("        ");
-            for s in self.stack.iter() {
-                print!("[ ");
-                crate::value::print_value(s);
-                print!(" ]");
+            // we're between instructions, so every live value is
+            // somewhere the collector can see it
+            if self.heap.should_collect() {
+                crate::alloc::collect_garbage(self);
             }
-            println!("");
 
-            crate::debug::disassemble_instruction(
-                &chunk,
-                self.ip);
+            // the function we're executing. we hold our own
+            // reference so we can read its constants while touching
+            // the stack
+            let function = Rc::clone(&self.frames.last().unwrap().closure.function);
+            let chunk = &function.chunk;
+
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.instruction(&self.stack,
+                                   chunk,
+                                   self.frames.last().unwrap().ip);
+            }
 
             let instruction: Option<crate::chunk::Opcode> =
                 crate::chunk::Opcode::from_u8(
-                    read_byte!(self, chunk)
+                    read_byte!(self)
                 );
 
             match instruction {
                 Some(crate::chunk::Opcode::OPRETURN) => {
-                    crate::value::print_value(&self.stack.pop().unwrap());
-                    println!();
-                    return InterpretResult::Ok },
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+
+                    if self.frames.is_empty() {
+                        return Ok(result)
+                    }
+
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                },
+
+                Some(crate::chunk::Opcode::OPCLOSURE) |
+                Some(crate::chunk::Opcode::OPCLOSURELONG) => {
+                    let ix = read_index!(self, instruction, OPCLOSURELONG);
+                    let function = match &chunk.constants.values[ix] {
+                        crate::value::ConstantType::FUNCTION(f) => Rc::clone(f),
+                        _ => return Err(self.runtime_error(
+                            "Closures must be made from functions.".to_string()))
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count as usize);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = read_byte!(self) == 1;
+                        let index = read_byte!(self) as usize;
+                        let frame = self.frames.last().unwrap();
+                        if is_local {
+                            let slot = frame.slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(Rc::clone(&frame.closure.upvalues[index]));
+                        }
+                    }
+
+                    self.stack.push(crate::value::ValueType::CLOSURE(
+                        self.heap.alloc_closure(crate::value::Closure {
+                            function,
+                            upvalues,
+                        })));
+                },
+
+                Some(crate::chunk::Opcode::OPGETUPVALUE) => {
+                    let index = read_byte!(self) as usize;
+                    let upvalue = Rc::clone(&self.frames.last().unwrap().closure.upvalues[index]);
+                    let v = match &*upvalue.borrow() {
+                        crate::value::Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        crate::value::Upvalue::Closed(v) => v.clone(),
+                    };
+                    self.stack.push(v)
+                },
+
+                Some(crate::chunk::Opcode::OPCLOSEUPVALUE) => {
+                    let slot = read_byte!(self) as usize;
+                    let base = self.frames.last().unwrap().slots;
+                    self.close_upvalues(base + slot);
+                },
+
+                Some(crate::chunk::Opcode::OPCALL) => {
+                    let argc = read_byte!(self);
+                    self.call_value(argc)?;
+                },
+
+                Some(crate::chunk::Opcode::OPTAILCALL) => {
+                    let argc = read_byte!(self);
+                    self.tail_call_value(argc)?;
+                },
 
                 // binary ops
-                Some(crate::chunk::Opcode::OPADD) =>
-                    number_op!(self, +),
+                Some(crate::chunk::Opcode::OPADD) => {
+                    // strings concatenate, everything else is maths
+                    let l = self.peek(1);
+                    let r = self.peek(0);
+                    match (l, r) {
+                        (crate::value::ValueType::STRING(lv),
+                         crate::value::ValueType::STRING(rv)) => {
+                            let s = format!("{}{}", lv, rv);
+                            let s = self.heap.alloc_string(s);
+                            self.stack.pop();
+                            self.stack.pop();
+                            self.stack.push(crate::value::ValueType::STRING(s));
+                        },
+                        _ => number_op!(self, checked_add, +)
+                    }
+                },
+                Some(crate::chunk::Opcode::OPNEGATE) => {
+                    let v = match self.stack.pop().unwrap() {
+                        crate::value::ValueType::INT(n) => match n.checked_neg() {
+                            Some(n) => int_val!(n),
+                            None => return Err(self.runtime_error(
+                                "Integer overflow.".to_string()))
+                        },
+                        crate::value::ValueType::FLOAT(n) => float_val!(-n),
+                        _ => return Err(self.runtime_error(
+                            "Operand to negate must be a number".to_string()))
+                    };
+                    self.stack.push(v)
+                },
                 Some(crate::chunk::Opcode::OPSUBTRACT) =>
-                    number_op!(self, -),
+                    number_op!(self, checked_sub, -),
                 Some(crate::chunk::Opcode::OPMULTIPLY) =>
-                    number_op!(self, *),
+                    number_op!(self, checked_mul, *),
                 Some(crate::chunk::Opcode::OPDIVIDE) =>
-                    number_op!(self, /),
+                    number_op!(self, checked_div, /),
                 Some(crate::chunk::Opcode::OPLT) =>
                     bool_op!(self, <),
                 Some(crate::chunk::Opcode::OPGT) =>
@@ -172,47 +576,39 @@ impl<'a> VM<'a> {
                         crate::value::ValueType::BOOL(is_falsey(v))
                     )
                 },
-                Some(crate::chunk::Opcode::OPSYM) => {
-                    let constant = &chunk
-                        .constants
-                        .values[read_byte!(self, chunk) as usize];
-
-                    let s = match constant {
-                        crate::value::ConstantType::SYMBOL(sym) =>{
-                            Some(sym)
-                        }
-                        _ => {
-                            print!("Symbols must be symbols\n");
-                            None
-                        }
-                    };
-
-                    let v = self.symbols.get(&s.unwrap().to_string());
-                    self.stack.push(v.unwrap().to_owned())
+                Some(crate::chunk::Opcode::OPGETGLOBAL) |
+                Some(crate::chunk::Opcode::OPGETGLOBALLONG) => {
+                    let slot = read_index!(self, instruction, OPGETGLOBALLONG);
+                    match &self.globals.values[slot] {
+                        Some(v) => self.stack.push(v.clone()),
+                        None => return Err(self.runtime_error(
+                            format!("Undefined symbol '{}'.", self.globals.names[slot]))
+                                           .with_help("define it first with (def name value)"))
+                    }
+                },
+                Some(crate::chunk::Opcode::OPGETLOCAL) => {
+                    let slot = read_byte!(self) as usize;
+                    let v = self.stack[self.frames.last().unwrap().slots + slot].clone();
+                    self.stack.push(v)
                 },
-                Some(crate::chunk::Opcode::OPCONSTANT) => {
+                Some(crate::chunk::Opcode::OPSETLOCAL) => {
+                    // leaves the value on the stack
+                    let slot = read_byte!(self) as usize;
+                    let base = self.frames.last().unwrap().slots;
+                    self.stack[base + slot] = self.stack.last().unwrap().clone();
+                },
+                Some(crate::chunk::Opcode::OPCONSTANT) |
+                Some(crate::chunk::Opcode::OPCONSTANTLONG) => {
 
                     // borrow a ConstantType from chunk
+                    let ix = read_index!(self, instruction, OPCONSTANTLONG);
                     let constant = &chunk
                         .constants
-                        .values[read_byte!(self, chunk) as usize];
+                        .values[ix];
 
-                    // Make a new ValueType, which points to the value
-                    // in the ConstantType. Push that onto our stack.
-                    self.stack.push(
-                        match constant {
-                            crate::value::ConstantType::INT(n) =>
-                                crate::value::ValueType::INT(*n),
-                            crate::value::ConstantType::FLOAT(n) =>
-                                crate::value::ValueType::FLOAT(*n),
-                            crate::value::ConstantType::STRING(s) =>
-                                crate::value::ValueType::STRING(&s),
-                            crate::value::ConstantType::SYMBOL(s) => {
-                                crate::value::ValueType::SYMBOL(&s)
-                            }
-
-                        }
-                    );
+                    // Make a new ValueType sharing the constant's
+                    // storage. Push that onto our stack.
+                    self.stack.push(constant.to_value());
 
                 },
                 Some(crate::chunk::Opcode::OPNIL) =>
@@ -222,17 +618,30 @@ impl<'a> VM<'a> {
                 Some(crate::chunk::Opcode::OPFALSE) =>
                     self.stack.push(crate::value::ValueType::BOOL(false)),
                 Some(crate::chunk::Opcode::OPEQUAL) => {
-                    let l = &self.stack.pop().unwrap();
-                    let r = &self.stack.pop().unwrap();
+                    // like the bool ops, with a count
+                    let n = read_byte!(self) as usize;
+                    let operands = self.stack.split_off(self.stack.len() - n);
+                    let equal = operands.windows(2)
+                        .all(|pair| crate::value::values_equal(&pair[0], &pair[1]));
                     self.stack.push(
-                        crate::value::ValueType::BOOL(values_equal(l, r))
+                        crate::value::ValueType::BOOL(equal)
                     )
                 }
 
                 Some(crate::chunk::Opcode::OPLEN) => {
 
                     let v = match self.stack.pop().unwrap() {
+                        // in chars, like `count`
                         crate::value::ValueType::STRING(s) => {
+                            crate::value::ValueType::INT(s.chars().count() as i64)
+                        },
+                        crate::value::ValueType::VECTOR(v) => {
+                            crate::value::ValueType::INT(v.len() as i64)
+                        },
+                        crate::value::ValueType::MAP(m) => {
+                            crate::value::ValueType::INT(m.len() as i64)
+                        },
+                        crate::value::ValueType::SET(s) => {
                             crate::value::ValueType::INT(s.len() as i64)
                         },
                         _ => {
@@ -245,66 +654,89 @@ impl<'a> VM<'a> {
 
                 Some(crate::chunk::Opcode::OPPRINT) => {
                     let v = &self.stack.pop().unwrap();
-                    print!("printing: {:?}", v);
+                    crate::value::print_value(v);
+                    println!();
                     self.stack.push(
                         crate::value::ValueType::NIL
                     )
                 }
 
+                Some(crate::chunk::Opcode::OPDUP) => {
+                    let v = self.stack.last().unwrap().clone();
+                    self.stack.push(v);
+                }
+
                 Some(crate::chunk::Opcode::OPPOP) => {
                     self.stack.pop();
                 }
 
-                Some(crate::chunk::Opcode::OPDEF) => {
+                Some(crate::chunk::Opcode::OPDEFINEGLOBAL) |
+                Some(crate::chunk::Opcode::OPDEFINEGLOBALLONG) => {
+                    let slot = read_index!(self, instruction, OPDEFINEGLOBALLONG);
                     let v = self.stack.pop().unwrap();
-                    let s = &self.stack.pop().unwrap();
-
-                    match s {
-                        crate::value::ValueType::SYMBOL(sym) =>{
-                            self.symbols.insert(sym.to_owned().to_string(), v);
-                        }
-                        _ => {
-                            print!("Symbols must be symbols\n")
-                        }
-                    }
-
+                    self.globals.values[slot] = Some(v);
                     self.stack.push(
                         crate::value::ValueType::NIL
                     )
                 }
 
-                Some(crate::chunk::Opcode::OPDEFSYM) => {
-
-                    let sym_const = &chunk
-                        .constants
-                        .values[read_byte!(self, chunk) as usize];
+                Some(crate::chunk::Opcode::OPVECTOR) => {
+                    let n = read_short!(self);
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    let v = self.heap.alloc_vector(crate::collections::init_vector(items));
+                    self.stack.push(crate::value::ValueType::VECTOR(v));
+                }
 
-                    let sym = match sym_const {
-                        crate::value::ConstantType::SYMBOL(s) =>
-                            Some(s),
-                        _ => None
-                    };
+                Some(crate::chunk::Opcode::OPMAP) => {
+                    let n = read_short!(self);
+                    let mut items = self.stack.split_off(self.stack.len() - n).into_iter();
+                    let mut entries = Vec::with_capacity(n / 2);
+                    while let (Some(k), Some(v)) = (items.next(), items.next()) {
+                        entries.push((k, v));
+                    }
+                    let m = self.heap.alloc_map(crate::collections::init_map(entries));
+                    self.stack.push(crate::value::ValueType::MAP(m));
+                }
 
-                    self.stack.push(
-                        crate::value::ValueType::SYMBOL(sym.unwrap()));
+                Some(crate::chunk::Opcode::OPSET) => {
+                    let n = read_short!(self);
+                    let items = self.stack.split_off(self.stack.len() - n);
+                    let s = self.heap.alloc_set(crate::collections::init_set(items));
+                    self.stack.push(crate::value::ValueType::SET(s));
                 }
 
+                // jump offsets are counted from the end of the
+                // jump instruction
+                Some(crate::chunk::Opcode::OPJUMPIFFALSE) => {
+                    let offset = read_short!(self);
 
-                Some(crate::chunk::Opcode::OPJMPIFFALSE) => {
-                    let jmp_to = read_byte!(self, chunk);
+                    let cond = &self.stack.pop().unwrap();
+                    if is_falsey(cond) {
+                        self.frames.last_mut().unwrap().ip += offset;
+                    }
+                }
+
+                Some(crate::chunk::Opcode::OPJUMPIFTRUE) => {
+                    let offset = read_short!(self);
 
                     let cond = &self.stack.pop().unwrap();
-                    if (is_falsey(cond)) {
-                        self.ip = jmp_to as usize;
+                    if !is_falsey(cond) {
+                        self.frames.last_mut().unwrap().ip += offset;
                     }
                 }
 
-                Some(crate::chunk::Opcode::OPJMP) => {
-                    let jmp_to = read_byte!(self, chunk);
-                    self.ip = jmp_to as usize;
+                Some(crate::chunk::Opcode::OPJUMP) => {
+                    let offset = read_short!(self);
+                    self.frames.last_mut().unwrap().ip += offset;
                 }
 
-                _ => return InterpretResult::CompileError,
+                Some(crate::chunk::Opcode::OPLOOP) => {
+                    let offset = read_short!(self);
+                    self.frames.last_mut().unwrap().ip -= offset;
+                }
+
+                _ => return Err(self.runtime_error(
+                    "Unknown opcode.".to_string())),
             }
         }
     }
@@ -314,23 +746,38 @@ fn is_falsey(v: &crate::value::ValueType) -> bool {
     is_nil!(*v) || (is_bool!(*v) && !(as_bool!(*v)))
 }
 
-fn values_equal(l: &crate::value::ValueType,
-                r: &crate::value::ValueType) -> bool {
-
-    match (l,r) {
-        (crate::value::ValueType::BOOL(lv),
-         crate::value::ValueType::BOOL(rv)) => { lv == rv },
-        (crate::value::ValueType::NIL,
-         crate::value::ValueType::NIL) => true,
-        (crate::value::ValueType::INT(lv),
-         crate::value::ValueType::INT(rv)) => { lv == rv },
-        (crate::value::ValueType::FLOAT(lv),
-         crate::value::ValueType::FLOAT(rv)) => { lv == rv },
-        (_,_) => false
+#[cfg(test)]
+mod tests {
+    fn run(source: &str) -> crate::value::ValueType {
+        let mut vm = super::init_vm();
+        match vm.interpret(source, "<test>") {
+            Ok(value) => value,
+            Err(err) => panic!("{}", err)
+        }
     }
 
-}
+    // far deeper than the frame limit, so this only finishes if every
+    // call reuses its caller's frame
+    #[test]
+    fn self_tail_calls_run_in_constant_space() {
+        let value = run("
+            (def f (fn [n acc] (if (= n 0) acc (f (- n 1) (+ acc 1)))))
+            (f 1000000 0)");
+        assert!(matches!(value, crate::value::ValueType::INT(1000000)));
+    }
 
-fn runtime_error(msg: &str) {
-    println!("There was an error: {}", msg);
+    #[test]
+    fn mutual_tail_calls_run_in_constant_space() {
+        let value = run("
+            (def ev (fn [n] (if (= n 0) true (od (- n 1)))))
+            (def od (fn [n] (if (= n 0) false (ev (- n 1)))))
+            [(ev 1000000) (od 1000001)]");
+        match value {
+            crate::value::ValueType::VECTOR(v) => {
+                assert!(matches!(v.get(0), Some(crate::value::ValueType::BOOL(true))));
+                assert!(matches!(v.get(1), Some(crate::value::ValueType::BOOL(true))));
+            },
+            other => panic!("expected a vector, got a {}", crate::value::type_name(&other))
+        }
+    }
 }